@@ -29,19 +29,38 @@
  */
 
 use crate::{
-    arch::{x86_64::msr, ThisArch},
+    arch::{
+        x86_64::{
+            fpu::{self, FpuState},
+            lapic::TimerCpu,
+            lbr, mce, memtype, msr,
+            pmu::{self, PmuCpu},
+            spec,
+            stack::KernelStack,
+            syscall,
+            tlb::PcidCpu,
+            topology::{self, Cache, Topology, MAX_CACHES},
+            trap,
+        },
+        ThisArch,
+    },
     cpu::{self, Cpu},
     sync::lazy::Lazy,
+    trap::DisableToken,
+    vm::VirtAddr,
 };
 use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
     mem::{self, size_of},
-    ptr::addr_of_mut,
+    ptr::{addr_of, addr_of_mut, NonNull},
 };
+use core::ops::Range;
 use cpu_features::{CpuFeatures, CpuInfo};
 use memoffset::offset_of;
 
 impl cpu::ArchCpu for ThisArch {
     type Data = CpuData;
+    type Identity = CpuIdentity;
 
     #[inline(always)]
     fn get_current_cpu() -> *mut Cpu {
@@ -63,8 +82,31 @@ impl cpu::ArchCpu for ThisArch {
 pub struct CpuData {
     this_cpu: *mut Cpu,
     gdt: Gdt,
-    tss: Tss,
+    pub(super) tss: Tss,
     cpu_info: CpuInfo,
+    ist_stacks: [Option<KernelStack>; NUM_IST_STACKS],
+    /// Stack of the CPU's initial context
+    boot_stack: Option<KernelStack>,
+    /// Guard page of the stack currently installed in `Tss::privileged_stack_table[0]`
+    kernel_stack_guard: Option<Range<VirtAddr>>,
+    /// Scratch space for the user stack pointer on entry to a system call
+    pub(super) user_rsp: usize,
+    /// Extended state currently loaded in the registers
+    pub(super) fpu_owner: Option<NonNull<FpuState>>,
+    /// Set while the kernel is using the FPU, see [`fpu::kernel_fpu_begin()`]
+    pub(super) kernel_fpu: Option<DisableToken>,
+    pub(super) pmu: PmuCpu,
+    /// PCIDs assigned to address spaces on this CPU
+    pub(super) pcids: PcidCpu,
+    /// State of the Local APIC timer
+    pub(super) timer: TimerCpu,
+}
+
+/// Description of a CPU which other CPUs may read, see [`cpu::CpuRef`]
+pub struct CpuIdentity {
+    apic_id:  u32,
+    topology: Topology,
+    caches:   [Option<Cache>; MAX_CACHES],
 }
 
 #[repr(C)]
@@ -80,8 +122,17 @@ struct Gdt {
     tss_hi: usize,
 }
 
+pub(super) const SEL_KCODE: u16 = offset_of!(Gdt, kernel_code) as u16;
+pub(super) const SEL_KDATA: u16 = offset_of!(Gdt, kernel_data) as u16;
+pub(super) const SEL_TSS: u16 = offset_of!(Gdt, tss) as u16;
+pub(super) const SEL_UCODE32: u16 = offset_of!(Gdt, user_code_32) as u16 | 3;
+pub(super) const SEL_UCODE: u16 = offset_of!(Gdt, user_code) as u16 | 3;
+// `SYSRET` loads SS from the descriptor after `user_code_32`. Data descriptors are all
+// the same in long mode, so use that one everywhere for consistency.
+pub(super) const SEL_UDATA: u16 = offset_of!(Gdt, user_data_32) as u16 | 3;
+
 #[repr(C, packed)]
-struct Tss {
+pub(super) struct Tss {
     reserved0: u32,
     pub(super) privileged_stack_table: [usize; 3],
     pub(super) interrupt_stack_table: [usize; 8],
@@ -89,6 +140,65 @@ struct Tss {
     io_map_base: u16,
 }
 
+impl CpuIdentity {
+    /// Returns the APIC ID of this CPU
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// Returns the location of this CPU in the package, core and SMT hierarchy
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// Returns the caches of this CPU, starting at the lowest level
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.caches.iter().flatten()
+    }
+}
+
+/// Interrupt Stack Table assignments
+///
+/// These exceptions can occur at any time, including when the current stack cannot be
+/// trusted, and are always delivered on a dedicated stack.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Ist {
+    DoubleFault  = 1,
+    Nmi          = 2,
+    MachineCheck = 3,
+    Debug        = 4,
+}
+
+const NUM_IST_STACKS: usize = 4;
+
+impl Ist {
+    const ALL: [Ist; NUM_IST_STACKS] = [Ist::DoubleFault, Ist::Nmi, Ist::MachineCheck, Ist::Debug];
+
+    const fn name(self) -> &'static str {
+        match self {
+            Ist::DoubleFault => "double fault",
+            Ist::Nmi => "NMI",
+            Ist::MachineCheck => "machine check",
+            Ist::Debug => "debug",
+        }
+    }
+}
+
+const IST_STACK_SIZE: usize = 0x4000;
+const KERNEL_STACK_SIZE: usize = 0x10000;
+
+/// Stack used by every IST entry of the boot processor until [`init_stacks()`] is called
+///
+/// The IDT routes exceptions through the IST as soon as it is loaded, so the entries must
+/// point somewhere valid before it is possible to allocate the guarded per-CPU stacks. Only
+/// the boot processor uses it, application processors have their stacks allocated by
+/// [`alloc_stacks()`] before they are started.
+#[repr(C, align(16))]
+struct BootIstStack([u8; IST_STACK_SIZE]);
+
+static mut BOOT_IST_STACK: BootIstStack = BootIstStack([0; IST_STACK_SIZE]);
+
 pub static CPU_FEATURES: Lazy<CpuFeatures> = Lazy::new(|| unimplemented!());
 
 pub unsafe fn early_init(cpu: *mut Cpu) {
@@ -97,14 +207,56 @@ pub unsafe fn early_init(cpu: *mut Cpu) {
     // Set up the self-reference.
     (*mdcpu).this_cpu = cpu;
 
+    // The x2APIC ID is reported by leaf 0xb even when the Local APIC is in xAPIC mode,
+    // in which case it is the same as the 8-bit ID.
+    let apic_id = if __cpuid(0).eax >= 0xb && __cpuid_count(0xb, 0).ebx != 0 {
+        __cpuid_count(0xb, 0).edx
+    } else {
+        __cpuid(1).ebx >> 24
+    };
+    addr_of_mut!((*cpu).md_identity).write(CpuIdentity {
+        apic_id,
+        topology: topology::detect(apic_id),
+        caches: topology::caches(),
+    });
+
     // Initialize the TSS. Set `io_map_base` to the size of the TSS to disable
     // the I/O Permission Bitmap.
+    //
+    // The boot processor runs alone until it starts the others, its IST entries share the
+    // boot stack until `init_stacks()` allocates the real ones.
+    let mut interrupt_stack_table = [0; 8];
+    if msr::ApicBase::read().contains(msr::ApicBase::BSP) {
+        addr_of_mut!((*mdcpu).ist_stacks).write([None, None, None, None]);
+        addr_of_mut!((*mdcpu).boot_stack).write(None);
+
+        let boot_ist_top = addr_of!(BOOT_IST_STACK) as usize + size_of::<BootIstStack>();
+        for ist in Ist::ALL {
+            interrupt_stack_table[ist as usize] = boot_ist_top;
+        }
+    } else {
+        let ist_stacks = &*addr_of!((*mdcpu).ist_stacks);
+        for ist in Ist::ALL {
+            let stack = ist_stacks[ist as usize - 1].as_ref();
+            interrupt_stack_table[ist as usize] = stack.expect("missing IST stack").top().0;
+        }
+    }
+
     let tss = addr_of_mut!((*mdcpu).tss);
     tss.write(Tss {
+        interrupt_stack_table,
         io_map_base: size_of::<Tss>() as u16,
         ..mem::zeroed()
     });
 
+    addr_of_mut!((*mdcpu).kernel_stack_guard).write(None);
+    addr_of_mut!((*mdcpu).user_rsp).write(0);
+    addr_of_mut!((*mdcpu).fpu_owner).write(None);
+    addr_of_mut!((*mdcpu).kernel_fpu).write(None);
+    addr_of_mut!((*mdcpu).pmu).write(PmuCpu::new());
+    addr_of_mut!((*mdcpu).pcids).write(PcidCpu::new());
+    addr_of_mut!((*mdcpu).timer).write(TimerCpu::new());
+
     let tss_base = tss as usize;
     let tss_limit = size_of::<Tss>() - 1;
     let tss_desc_hi = tss_base >> 32;
@@ -188,11 +340,96 @@ pub unsafe fn early_init(cpu: *mut Cpu) {
 
         inlateout(reg) gdt => _,
         gdt_limit = const size_of::<Gdt>() - 1,
-        SEL_KCODE = const offset_of!(Gdt, kernel_code),
-        SEL_KDATA = const offset_of!(Gdt, kernel_data),
-        SEL_TSS   = const offset_of!(Gdt, tss),
+        SEL_KCODE = const SEL_KCODE,
+        SEL_KDATA = const SEL_KDATA,
+        SEL_TSS   = const SEL_TSS,
     );
 
+    trap::load_idt();
+
     msr::wrmsr(msr::IA32_GS_BASE, cpu as u64);
     msr::wrmsr(msr::IA32_KERNEL_GS_BASE, 0);
+
+    memtype::init();
+    syscall::init();
+    fpu::init();
+    mce::init();
+    pmu::init();
+    spec::init();
+
+    #[cfg(feature = "debug_lbr")]
+    lbr::init();
+
+    cpu::register(cpu);
+}
+
+/// Allocate the guarded interrupt stacks and initial kernel stack for `cpu`
+///
+/// The boot processor calls this for each application processor before starting it, so
+/// no two CPUs ever share an IST stack. The storage of `cpu` may be uninitialized.
+pub unsafe fn alloc_stacks(cpu: *mut Cpu) {
+    let mdcpu = addr_of_mut!((*cpu).md_data);
+    let ist_stacks = Ist::ALL.map(|_| Some(KernelStack::new(IST_STACK_SIZE)));
+    addr_of_mut!((*mdcpu).ist_stacks).write(ist_stacks);
+    addr_of_mut!((*mdcpu).boot_stack).write(Some(KernelStack::new(KERNEL_STACK_SIZE)));
+}
+
+/// Install the guarded interrupt stacks and initial kernel stack of this CPU
+///
+/// The boot processor's stacks are allocated here, which requires the kernel HAT, so it
+/// cannot be done in [`early_init()`].
+pub unsafe fn init_stacks(cpu: *mut Cpu) {
+    if (*cpu).md_data.boot_stack.is_none() {
+        alloc_stacks(cpu);
+    }
+
+    let mdcpu = &mut (*cpu).md_data;
+    let mut interrupt_stack_table = mdcpu.tss.interrupt_stack_table;
+    for ist in Ist::ALL {
+        let stack = mdcpu.ist_stacks[ist as usize - 1].as_ref().unwrap();
+        interrupt_stack_table[ist as usize] = stack.top().0;
+    }
+    mdcpu.tss.interrupt_stack_table = interrupt_stack_table;
+
+    set_kernel_stack(mdcpu.boot_stack.as_ref().unwrap());
+}
+
+/// Returns the top of the stack of the CPU's initial context
+///
+/// # Panics
+///
+/// This function will panic if called before [`init_stacks()`].
+pub(super) unsafe fn boot_stack_top(cpu: *mut Cpu) -> VirtAddr {
+    (*cpu).md_data.boot_stack.as_ref().unwrap().top()
+}
+
+/// Set the stack to switch to when entering the kernel from user mode
+///
+/// This must be called whenever the current CPU switches to a context with a different
+/// kernel stack.
+pub fn set_kernel_stack(stack: &KernelStack) {
+    let mdcpu = unsafe { &mut (*cpu::this_cpu()).md_data };
+    let mut privileged_stack_table = mdcpu.tss.privileged_stack_table;
+    privileged_stack_table[0] = stack.top().0;
+    mdcpu.tss.privileged_stack_table = privileged_stack_table;
+    mdcpu.kernel_stack_guard = Some(stack.guard_page());
+}
+
+/// Returns the name of the stack whose guard page contains `addr`, if any
+pub(super) fn find_overflowed_stack(addr: VirtAddr) -> Option<&'static str> {
+    let mdcpu = unsafe { &(*cpu::this_cpu()).md_data };
+
+    for ist in Ist::ALL {
+        if let Some(stack) = &mdcpu.ist_stacks[ist as usize - 1] {
+            if stack.guard_page().contains(&addr) {
+                return Some(ist.name());
+            }
+        }
+    }
+
+    mdcpu
+        .kernel_stack_guard
+        .as_ref()
+        .filter(|guard| guard.contains(&addr))
+        .map(|_| "kernel")
 }
@@ -0,0 +1,95 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Exception Tables
+//!
+//! Code which expects an instruction to fault, like [`msr::rdmsr_safe()`], records the address
+//! of the instruction and where to resume in the `.extable` section. When a fault in kernel
+//! mode hits one of these instructions, the trap handler resumes at the fixup address instead
+//! of treating it as a crash.
+//!
+//! Each entry holds two 32-bit offsets relative to themselves, which keeps the table small and
+//! free of relocations:
+//!
+//! ```text
+//! .pushsection .extable, "a"
+//! .balign 4
+//! .long   2b - .
+//! .long   3b - .
+//! .popsection
+//! ```
+//!
+//! [`msr::rdmsr_safe()`]: super::msr::rdmsr_safe
+
+use core::slice;
+
+use super::trap::TrapFrame;
+
+#[repr(C)]
+struct Entry {
+    insn:  i32,
+    fixup: i32,
+}
+
+impl Entry {
+    fn insn(&self) -> usize {
+        (&self.insn as *const i32 as usize).wrapping_add_signed(self.insn as isize)
+    }
+
+    fn fixup(&self) -> usize {
+        (&self.fixup as *const i32 as usize).wrapping_add_signed(self.fixup as isize)
+    }
+}
+
+extern "C" {
+    static __extable_start: Entry;
+    static __extable_end: Entry;
+}
+
+fn entries() -> &'static [Entry] {
+    unsafe {
+        let start: *const Entry = &__extable_start;
+        let end: *const Entry = &__extable_end;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Resume at the fixup for the faulting instruction, if there is one
+///
+/// Returns `true` if `frame` was redirected.
+pub(super) fn fixup(frame: &mut TrapFrame) -> bool {
+    match entries().iter().find(|entry| entry.insn() == frame.rip) {
+        Some(entry) => {
+            frame.rip = entry.fixup();
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,483 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! FPU, SSE and AVX State
+//!
+//! The kernel itself is built without SIMD, so the only extended state belongs to user
+//! threads. Each thread owns an [`FpuState`], which is loaded into the registers by
+//! [`FpuState::activate()`] and written back whenever someone else needs them. A state still
+//! loaded on another CPU, because its thread migrated or exited, is fetched back from that
+//! CPU with an IPI.
+//!
+//! The save area is managed with the best mechanism the processor supports:
+//!
+//! - `XSAVES`/`XRSTORS`, using the compacted format and including supervisor state
+//!   enabled in `IA32_XSS`,
+//! - `XSAVEOPT`/`XRSTOR`, or plain `XSAVE`,
+//! - `FXSAVE`/`FXRSTOR`, which covers only x87 and SSE state.
+//!
+//! Kernel code which wants to use SIMD instructions must do so between
+//! [`kernel_fpu_begin()`] and [`kernel_fpu_end()`].
+
+use alloc::alloc::{alloc_zeroed, dealloc};
+use core::{
+    alloc::Layout,
+    arch::x86_64::{__cpuid, __cpuid_count},
+    ptr::{addr_of_mut, NonNull},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use x86_64::control::Cr4;
+
+use super::{
+    lapic::{self, IpiTarget},
+    msr,
+    trap::{register_handler, TrapFrame},
+};
+use crate::{cpu, sync::lazy::Lazy, trap};
+
+/// Asks a CPU to save the state loaded in its registers, see [`FpuState::flush_remote()`]
+const VECTOR_FPU_FLUSH: u8 = 0xf2;
+
+/// Value of [`FpuState::cpu`] while the state is not loaded anywhere
+const NO_CPU: usize = usize::MAX;
+
+// XSAVE state components
+const XFEATURE_X87: u64 = 1 << 0;
+const XFEATURE_SSE: u64 = 1 << 1;
+const XFEATURE_AVX: u64 = 1 << 2;
+const XFEATURE_AVX512: u64 = 0b111 << 5;
+const XFEATURE_PKRU: u64 = 1 << 9;
+const XFEATURE_CET_USER: u64 = 1 << 11;
+const XFEATURE_CET_SUPERVISOR: u64 = 1 << 12;
+
+/// User state components which we know how to manage
+///
+/// AMX tile data is left out, its state is larger than everything else combined and must
+/// be requested explicitly.
+const XCR0_WANTED: u64 =
+    XFEATURE_X87 | XFEATURE_SSE | XFEATURE_AVX | XFEATURE_AVX512 | XFEATURE_PKRU;
+
+/// Supervisor state components saved with the thread when `XSAVES` is available
+const XSS_WANTED: u64 = XFEATURE_CET_USER | XFEATURE_CET_SUPERVISOR;
+
+/// Size of the legacy `FXSAVE` region plus the XSAVE header
+const XSAVE_HEADER_END: usize = 576;
+const XSAVE_HEADER_OFFSET: usize = 512;
+const XCOMP_BV_COMPACTED: u64 = 1 << 63;
+
+const FCW_DEFAULT: u16 = 0x037f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+const MXCSR_OFFSET: usize = 24;
+
+const CR0_MP: usize = 1 << 1;
+const CR0_EM: usize = 1 << 2;
+const CR0_TS: usize = 1 << 3;
+const CR0_NE: usize = 1 << 5;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Method {
+    Xsaves,
+    Xsaveopt,
+    Xsave,
+    Fxsave,
+}
+
+struct FpuConfig {
+    method: Method,
+    xcr0:   u64,
+    xss:    u64,
+    /// Size of an `FpuState` save area
+    size:   usize,
+}
+
+static FPU_CONFIG: Lazy<FpuConfig> = Lazy::new(FpuConfig::detect);
+
+impl FpuConfig {
+    fn detect() -> FpuConfig {
+        let leaf1 = unsafe { __cpuid(1) };
+        let has_xsave = leaf1.ecx & (1 << 26) != 0;
+
+        let config = if has_xsave {
+            let leaf_d0 = unsafe { __cpuid_count(0xd, 0) };
+            let leaf_d1 = unsafe { __cpuid_count(0xd, 1) };
+
+            let xcr0 = (leaf_d0.eax as u64 | (leaf_d0.edx as u64) << 32) & XCR0_WANTED;
+            // The AVX-512 components must be enabled together, or not at all.
+            let xcr0 = match xcr0 & XFEATURE_AVX512 {
+                XFEATURE_AVX512 => xcr0,
+                _ => xcr0 & !XFEATURE_AVX512,
+            };
+
+            if leaf_d1.eax & (1 << 3) != 0 {
+                let xss = (leaf_d1.ecx as u64 | (leaf_d1.edx as u64) << 32) & XSS_WANTED;
+                FpuConfig {
+                    method: Method::Xsaves,
+                    xcr0,
+                    xss,
+                    size: compacted_size(xcr0 | xss),
+                }
+            } else {
+                FpuConfig {
+                    method: match leaf_d1.eax & 1 {
+                        0 => Method::Xsave,
+                        _ => Method::Xsaveopt,
+                    },
+                    xcr0,
+                    xss: 0,
+                    size: standard_size(xcr0),
+                }
+            }
+        } else {
+            FpuConfig {
+                method: Method::Fxsave,
+                xcr0: 0,
+                xss: 0,
+                size: 512,
+            }
+        };
+
+        log::info!(
+            "fpu: using {:?}, xcr0={:#x}, xss={:#x}, {} byte save area",
+            config.method,
+            config.xcr0,
+            config.xss,
+            config.size,
+        );
+
+        config
+    }
+
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.size, 64).unwrap()
+    }
+}
+
+/// Returns the size of the standard format save area containing `features`
+fn standard_size(features: u64) -> usize {
+    (2..64)
+        .filter(|&i| features & (1 << i) != 0)
+        .map(|i| {
+            let leaf = unsafe { __cpuid_count(0xd, i) };
+            leaf.ebx as usize + leaf.eax as usize
+        })
+        .fold(XSAVE_HEADER_END, usize::max)
+}
+
+/// Returns the size of the compacted format save area containing `features`
+///
+/// Components are packed in order, some of them aligned to 64 bytes.
+fn compacted_size(features: u64) -> usize {
+    (2..64)
+        .filter(|&i| features & (1 << i) != 0)
+        .fold(XSAVE_HEADER_END, |offset, i| {
+            let leaf = unsafe { __cpuid_count(0xd, i) };
+            let offset = match leaf.ecx & 2 {
+                0 => offset,
+                _ => (offset + 63) & !63,
+            };
+            offset + leaf.eax as usize
+        })
+}
+
+/// Enable the FPU and extended state on this CPU
+pub(super) unsafe fn init() {
+    let config = &*FPU_CONFIG;
+
+    let mut cr0: usize;
+    asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+    cr0 = (cr0 & !(CR0_EM | CR0_TS)) | CR0_MP | CR0_NE;
+    asm!("mov cr0, {}", in(reg) cr0, options(nomem, nostack, preserves_flags));
+
+    let mut cr4 = Cr4::read();
+    cr4 |= Cr4::OSFXSR | Cr4::OSXMMEXCPT;
+    if config.method != Method::Fxsave {
+        cr4 |= Cr4::OSXSAVE;
+    }
+    cr4.write();
+
+    if config.method != Method::Fxsave {
+        asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") config.xcr0 as u32,
+            in("edx") (config.xcr0 >> 32) as u32,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    if config.method == Method::Xsaves {
+        msr::wrmsr(msr::IA32_XSS, config.xss);
+    }
+
+    asm!("fninit", options(nomem, nostack, preserves_flags));
+}
+
+/// Set up the IPI used to fetch state loaded on another CPU
+pub(super) fn init_flush_ipi() {
+    register_handler(VECTOR_FPU_FLUSH, |_: &mut TrapFrame| unsafe { service_flush() });
+}
+
+/// Save the state loaded on this CPU if another CPU is waiting for it
+///
+/// Must be called with interrupts disabled.
+unsafe fn service_flush() {
+    let mdcpu = addr_of_mut!((*cpu::this_cpu()).md_data);
+    let Some(owner) = (*mdcpu).fpu_owner else {
+        return;
+    };
+    let owner = owner.as_ref();
+    if !owner.flush_requested.load(Ordering::Acquire) {
+        return;
+    }
+
+    // Inside a kernel FPU section the state has already been saved, and the registers hold
+    // the kernel's values.
+    if (*mdcpu).kernel_fpu.is_none() {
+        owner.save();
+    }
+    (*mdcpu).fpu_owner = None;
+    owner.cpu.store(NO_CPU, Ordering::Release);
+}
+
+/// Saved extended register state of a thread
+pub struct FpuState {
+    area:            NonNull<u8>,
+    /// CPU whose registers hold this state, or [`NO_CPU`]
+    cpu:             AtomicUsize,
+    /// Set while another CPU is waiting for this state to be saved
+    flush_requested: AtomicBool,
+}
+
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// Create a new save area, holding the initial state of every component
+    pub fn new() -> FpuState {
+        let config = &*FPU_CONFIG;
+        let area = unsafe { alloc_zeroed(config.layout()) };
+        let area = NonNull::new(area).expect("out of memory allocating fpu state");
+
+        unsafe {
+            area.as_ptr().cast::<u16>().write(FCW_DEFAULT);
+            area.as_ptr()
+                .add(MXCSR_OFFSET)
+                .cast::<u32>()
+                .write(MXCSR_DEFAULT);
+
+            // `XSTATE_BV` is left clear, so `XRSTOR` loads the initial configuration of every
+            // component. `XRSTORS` additionally requires the format to be given.
+            if config.method == Method::Xsaves {
+                area.as_ptr()
+                    .add(XSAVE_HEADER_OFFSET + 8)
+                    .cast::<u64>()
+                    .write(XCOMP_BV_COMPACTED | config.xcr0 | config.xss);
+            }
+        }
+
+        FpuState {
+            area,
+            cpu: AtomicUsize::new(NO_CPU),
+            flush_requested: AtomicBool::new(false),
+        }
+    }
+
+    /// Load this state into the registers of the current CPU
+    ///
+    /// The state previously loaded on this CPU, if any, is saved first. This state will be
+    /// saved again the next time another one is activated. If it is still loaded on another
+    /// CPU, that CPU saves it first.
+    ///
+    /// # Safety
+    ///
+    /// The `FpuState` must not be moved while it is active. Dropping it is fine.
+    pub unsafe fn activate(&mut self) {
+        let token = trap::disable();
+        let this = cpu::this_cpu();
+        let mdcpu = addr_of_mut!((*this).md_data);
+
+        if (*mdcpu).fpu_owner != Some(NonNull::from(&*self)) {
+            self.flush_remote();
+            if let Some(owner) = (*mdcpu).fpu_owner {
+                let owner = owner.as_ref();
+                owner.save();
+                owner.cpu.store(NO_CPU, Ordering::Release);
+            }
+            self.restore();
+            self.cpu.store((*this).cpu_id, Ordering::Relaxed);
+            (*mdcpu).fpu_owner = Some(NonNull::from(&*self));
+        }
+
+        trap::enable(token);
+    }
+
+    /// Wait until this state is not loaded on any other CPU
+    ///
+    /// Must be called with interrupts disabled.
+    unsafe fn flush_remote(&self) {
+        let owner = self.cpu.load(Ordering::Acquire);
+        if owner == NO_CPU || owner == (*cpu::this_cpu()).cpu_id {
+            return;
+        }
+
+        self.flush_requested.store(true, Ordering::Release);
+        if let Some(cpu) = cpu::get(owner) {
+            lapic::send_ipi(IpiTarget::Apic(cpu.identity.apic_id()), VECTOR_FPU_FLUSH);
+        }
+
+        // The owner may in turn be waiting for us with interrupts disabled.
+        while self.cpu.load(Ordering::Acquire) != NO_CPU {
+            service_flush();
+            core::hint::spin_loop();
+        }
+        self.flush_requested.store(false, Ordering::Relaxed);
+    }
+
+    unsafe fn save(&self) {
+        let area = self.area.as_ptr();
+        match FPU_CONFIG.method {
+            Method::Xsaves => asm!(
+                "xsaves64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            Method::Xsaveopt => asm!(
+                "xsaveopt64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            Method::Xsave => asm!(
+                "xsave64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            Method::Fxsave => {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags))
+            }
+        }
+    }
+
+    unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        match FPU_CONFIG.method {
+            Method::Xsaves => asm!(
+                "xrstors64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            Method::Xsaveopt | Method::Xsave => asm!(
+                "xrstor64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags),
+            ),
+            Method::Fxsave => {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags))
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> FpuState {
+        FpuState::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // Forget the registers if they belong to us, there is nothing left to save them to.
+        // Another CPU still holding the state must let go of it before the area is freed.
+        let token = trap::disable();
+        unsafe {
+            let mdcpu = addr_of_mut!((*cpu::this_cpu()).md_data);
+            if (*mdcpu).fpu_owner == Some(NonNull::from(&*self)) {
+                (*mdcpu).fpu_owner = None;
+            } else {
+                self.flush_remote();
+            }
+        }
+        trap::enable(token);
+
+        unsafe { dealloc(self.area.as_ptr(), FPU_CONFIG.layout()) };
+    }
+}
+
+/// Begin a section of kernel code which uses the FPU or SIMD registers
+///
+/// The current thread's state is saved and the registers are reset. Interrupts are disabled
+/// until the matching call to [`kernel_fpu_end()`]. Sections may not be nested.
+pub fn kernel_fpu_begin() {
+    let token = trap::disable();
+    let mdcpu = unsafe { &mut (*cpu::this_cpu()).md_data };
+    assert!(
+        mdcpu.kernel_fpu.is_none(),
+        "kernel_fpu_begin() called twice"
+    );
+
+    unsafe {
+        if let Some(owner) = mdcpu.fpu_owner {
+            owner.as_ref().save();
+        }
+        asm!(
+            "
+                fninit
+                ldmxcsr [{}]
+            ",
+            in(reg) &MXCSR_DEFAULT as *const u32,
+            options(nostack, preserves_flags),
+        );
+    }
+
+    mdcpu.kernel_fpu = Some(token);
+}
+
+/// End a section started by [`kernel_fpu_begin()`]
+pub fn kernel_fpu_end() {
+    let mdcpu = unsafe { &mut (*cpu::this_cpu()).md_data };
+    let token = mdcpu
+        .kernel_fpu
+        .take()
+        .expect("kernel_fpu_end() called without kernel_fpu_begin()");
+
+    if let Some(owner) = mdcpu.fpu_owner {
+        unsafe { owner.as_ref().restore() };
+    }
+
+    trap::enable(token);
+}
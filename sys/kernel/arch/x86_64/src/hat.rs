@@ -33,15 +33,23 @@
 use core::{
     cmp, fmt,
     ops::{self, Index, IndexMut, Range},
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use cpu_features::CpuFeat;
 use x86_64::{control::Cr4, msr::Efer};
 
-use super::cpu::{self, CPU_FEATURES};
+use super::{
+    cpu::{self, CPU_FEATURES},
+    memtype::CacheMode,
+    spec,
+    tlb::{self, CpuMask},
+};
 use crate::{
     arch::ThisArch,
+    cpu::this_cpu,
     sync::{lazy::Lazy, mutex::MutexKind, Mutex},
+    trap,
     util::{bootstrap_cell::BootstrapCell, pow2, size_of},
     vm::{self, page::PMAP_QUEUE, PhysAddr, Prot, VirtAddr, PAGE_SIZE},
 };
@@ -71,6 +79,136 @@ pub enum PageSize {
     Size1GiB = 2,
 }
 
+impl PageSize {
+    const fn from_level(level: usize) -> PageSize {
+        match level {
+            0 => PageSize::Size4KiB,
+            1 => PageSize::Size2MiB,
+            _ => PageSize::Size1GiB,
+        }
+    }
+
+    /// Returns the size of the page in bytes
+    pub const fn size(self) -> usize {
+        level_size(self as usize)
+    }
+}
+
+impl fmt::Display for PageSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            PageSize::Size4KiB => "4K",
+            PageSize::Size2MiB => "2M",
+            PageSize::Size1GiB => "1G",
+        })
+    }
+}
+
+/// Returns the size of the region translated by an entry at `level`
+const fn level_size(level: usize) -> usize {
+    1 << (PAGE_SHIFT_4K as usize + 9 * level)
+}
+
+/// A contiguous run of translations with the same attributes
+///
+/// Both the virtual and physical addresses are contiguous over the whole run, which is made
+/// up of pages of a single size.
+#[derive(Clone, Debug)]
+pub struct Mapping {
+    pub virt:      VirtAddr,
+    pub phys:      PhysAddr,
+    /// Size of the run in bytes
+    pub size:      usize,
+    pub page_size: PageSize,
+    pub prot:      Prot,
+}
+
+/// Iterator over the translations in a range of a [`Hat`], returned by [`Hat::mappings()`]
+pub struct Mappings<'a> {
+    hat:  &'a Hat,
+    next: usize,
+    end:  usize,
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let mut run: Option<Mapping> = None;
+
+        while self.next < self.end {
+            let virt = VirtAddr(self.next);
+            if !virt.is_canonical() {
+                self.next = MMU_INFO.noncanonical_hole.end.0;
+                if run.is_some() {
+                    break;
+                }
+                continue;
+            }
+
+            let (level, entry_ptr) = self.hat.walk(virt);
+            let entry = unsafe { entry_ptr.read_volatile() };
+            let size = level_size(level);
+            let offset = self.next & (size - 1);
+            let size = cmp::min(size - offset, self.end - self.next);
+
+            if !entry.present() {
+                if run.is_some() {
+                    break;
+                }
+                self.next = self.next.checked_add(size).unwrap_or(self.end);
+                continue;
+            }
+
+            let page = Mapping {
+                virt,
+                phys: entry.leaf_addr(level) + offset,
+                size,
+                page_size: PageSize::from_level(level),
+                prot: entry.prot(),
+            };
+
+            match &mut run {
+                None => run = Some(page),
+                Some(run)
+                    if run.phys + run.size == page.phys
+                        && run.page_size == page.page_size
+                        && run.prot == page.prot =>
+                {
+                    run.size += page.size;
+                }
+                // This page starts the next run.
+                Some(_) => break,
+            }
+
+            self.next = self.next.checked_add(size).unwrap_or(self.end);
+        }
+
+        run
+    }
+}
+
+bitflags::bitflags! {
+    /// Usage bits the processor sets in the entries of pages it accesses
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct PageUsage : u8 {
+        /// The page has been read or written
+        const ACCESSED = 1 << 0;
+        /// The page has been written
+        const DIRTY    = 1 << 1;
+    }
+}
+
+/// A page which was found to have been used by [`Hat::test_and_clear()`]
+#[derive(Clone, Debug)]
+pub struct TouchedPage {
+    pub virt:      VirtAddr,
+    pub phys:      PhysAddr,
+    pub page_size: PageSize,
+    /// The usage bits which were set before they were cleared
+    pub usage:     PageUsage,
+}
+
 /// Hardware Address Translation Context
 ///
 /// # Kernel HAT
@@ -79,20 +217,46 @@ pub struct Hat {
     top_level:    &'static vm::Page,
     /// Tracks the number of pages of each size mapped into the address space
     mapped_pages: [usize; MAX_PAGE_LEVEL + 1],
-    pcid:         usize,
-    cr3:          usize,
+    /// Identifies the address space to the PCID allocator, see [`tlb::new_generation()`]
+    generation:   u64,
+    /// CPUs which may have translations from this address space cached
+    active:       CpuMask,
+    /// This is a user address space, rather than [`KERNEL_HAT`]
+    user:         bool,
 }
 
 unsafe impl Send for Hat {}
 
 impl Drop for Hat {
+    /// Free the page tables of the user half
+    ///
+    /// The kernel half is shared with every other address space and left alone. The pages
+    /// mapped by the tables are not freed, they belong to whoever mapped them.
     fn drop(&mut self) {
-        log::warn!("TODO: Hat::drop()");
+        // Nothing may walk the tables once they're freed, including from a stale PCID. CPUs
+        // which still have the address space loaded stay in the active set.
+        self.flush(VirtAddr(0)..MMU_INFO.noncanonical_hole.start);
+        assert!(self.active.is_empty(), "freeing an address space which is still loaded");
+
+        let top_level = self.top_level.addr.to_virt().as_ptr::<Pte>();
+        for i in 0..PTES_PER_TABLE / 2 {
+            let entry = unsafe { top_level.add(i).read_volatile() };
+            if entry.present() {
+                free_tables(entry.addr(), MMU_INFO.max_level as usize - 1);
+            }
+        }
+
+        free_page_table(self.top_level.addr);
     }
 }
 
 impl Hat {
-    pub fn new(asid: usize) -> Mutex<Hat> {
+    /// Create a new user address space, sharing the kernel half of [`KERNEL_HAT`]
+    pub fn new() -> Mutex<Hat> {
+        Self::alloc(true)
+    }
+
+    fn alloc(user: bool) -> Mutex<Hat> {
         // Allocate the top-level table and initialize the global entries.
         let page = vm::Page::alloc(&mut PMAP_QUEUE.lock()).unwrap();
         unsafe {
@@ -101,21 +265,29 @@ impl Hat {
                 .as_mut_ptr::<Pte>()
                 .copy_from(INITIAL_PTES.as_ptr(), PTES_PER_TABLE);
         }
-        let pcid = asid & 0xfff;
-        let cr3 = if MMU_INFO.pcide {
-            page.addr.0 | pcid
-        } else {
-            page.addr.0
-        };
         Mutex::new(MutexKind::Adaptive, Hat {
-            cr3,
-            pcid,
+            generation: tlb::new_generation(),
             top_level: page,
             mapped_pages: [0; MAX_PAGE_LEVEL + 1],
+            active: CpuMask::new(),
+            user,
         })
     }
 
     pub fn unmap_pages(&mut self, virt: VirtAddr, size: usize, page_size: PageSize) {
+        let mut batch = Batch::default();
+        self.unmap_pages_batched(virt, size, page_size, &mut batch);
+        self.finish(batch);
+    }
+
+    /// Remove translations like [`Hat::unmap_pages()`], leaving the shootdown to `batch`
+    fn unmap_pages_batched(
+        &mut self,
+        virt: VirtAddr,
+        size: usize,
+        page_size: PageSize,
+        batch: &mut Batch,
+    ) {
         let map_level = if page_size == PageSize::Size1GiB && !MMU_INFO.gigapages {
             PageSize::Size2MiB as usize
         } else {
@@ -132,6 +304,8 @@ impl Hat {
         while num_pages > 0 {
             let mut table = self.top_level.addr.to_virt().as_mut_ptr::<Pte>();
             let mut level = MMU_INFO.max_level;
+            // The entry referencing the table at each level, where occupancy is tracked
+            let mut parents = [None; MAX_LEVELS];
 
             loop {
                 let index = unmap_virt.index_for(level);
@@ -142,6 +316,7 @@ impl Hat {
                 if level != map_level as u32 {
                     assert!(entry.present());
                     assert!(!entry.huge());
+                    parents[level as usize - 1] = tracked_parent(entry_ptr, level, unmap_virt);
                     table = entry.addr().to_virt().as_mut_ptr();
                     level -= 1;
                     continue;
@@ -156,14 +331,44 @@ impl Hat {
                     unmap_virt += page_size;
                 }
 
+                self.mapped_pages[map_level] -= unmap_pages;
                 num_pages -= unmap_pages;
+                release_entries(&parents, map_level, unmap_pages, &mut batch.empty_tables);
                 break;
             }
         }
+
+        batch.add(virt..virt + size);
+    }
+
+    /// Perform the shootdown collected in `batch`, then free the tables it unlinked
+    fn finish(&self, batch: Batch) {
+        if let Some(range) = batch.range {
+            self.flush(range);
+        }
+
+        // Tables which became empty are freed once no CPU can be walking them.
+        for table in batch.empty_tables {
+            free_page_table(table);
+        }
+    }
+
+    /// Invalidate cached translations of `range` on every CPU
+    fn flush(&self, range: Range<VirtAddr>) {
+        let kernel = range.start >= MMU_INFO.noncanonical_hole.end;
+        let space = tlb::AddressSpace {
+            top_level:  self.top_level.addr,
+            generation: self.generation,
+            active:     &self.active,
+        };
+        tlb::shootdown(&space, range, kernel);
     }
 
     /// Insert a translation into the address space
     ///
+    /// Fails if part of the range is already mapped differently, after removing the
+    /// translations inserted up to that point.
+    ///
     /// # Panics
     ///
     /// When debug assertions are enabled, this function will panic if the requested translation
@@ -175,6 +380,7 @@ impl Hat {
         size: usize,
         page_size: PageSize,
         prot: Prot,
+        cache: CacheMode,
     ) -> Result<()> {
         let map_level = if page_size == PageSize::Size1GiB && !MMU_INFO.gigapages {
             PageSize::Size2MiB as usize
@@ -191,10 +397,18 @@ impl Hat {
         let mut map_virt = virt;
         let mut map_phys = phys;
         let mut num_pages = size / page_size;
+        // Runs of entries this call made present, removed again if it fails
+        let mut installed: Vec<Range<VirtAddr>> = Vec::new();
+        let leaf_flags = MMU_INFO.protmap[prot]
+            | MMU_INFO.pte_flags[map_level]
+            | cache_flags(cache, map_level)
+            | PteFlags::PRESENT;
 
         while num_pages > 0 {
             let mut table = self.top_level.addr.to_virt().as_mut_ptr::<Pte>();
             let mut level = MMU_INFO.max_level;
+            // The entry referencing the table at each level, where occupancy is tracked
+            let mut parents = [None; MAX_LEVELS];
             loop {
                 let table_index = map_virt.index_for(level);
                 let entry_ptr = unsafe { table.add(table_index) };
@@ -205,6 +419,9 @@ impl Hat {
                     if !entry.present() {
                         let page = alloc_page_table();
                         entry = Pte::new(page.addr, PteFlags::PRESENT);
+                        if let Some(parent) = parents[level as usize] {
+                            adjust_count(parent, 1);
+                        }
                     }
 
                     assert!(
@@ -216,6 +433,7 @@ impl Hat {
                     entry |= PARENT_FLAGS;
                     unsafe { entry_ptr.write_volatile(entry) };
 
+                    parents[level as usize - 1] = tracked_parent(entry_ptr, level, map_virt);
                     table = entry.addr().to_virt().as_mut_ptr();
                     level -= 1;
                     continue;
@@ -227,15 +445,31 @@ impl Hat {
                     let entry_ptr = unsafe { entry_ptr.add(i) };
                     let entry = unsafe { entry_ptr.read_volatile() };
 
-                    let new_entry = Pte::new(
-                        map_phys,
-                        MMU_INFO.protmap[prot] | MMU_INFO.pte_flags[map_level] | PteFlags::PRESENT,
-                    );
+                    let new_entry = Pte::new(map_phys, leaf_flags);
 
                     // Make sure the entry isn't already present.
                     if entry.present() && entry != new_entry {
+                        // Leave the address space as it was found.
+                        let mut batch = Batch::default();
+                        for run in installed {
+                            let size = run.end.0 - run.start.0;
+                            let page_size = PageSize::from_level(map_level);
+                            self.unmap_pages_batched(run.start, size, page_size, &mut batch);
+                        }
+                        self.release_path(&parents, map_level, map_virt, &mut batch);
+                        self.finish(batch);
                         return Err(Error::AlreadyMapped(entry.addr()));
                     }
+                    if !entry.present() {
+                        self.mapped_pages[map_level] += 1;
+                        if let Some(parent) = parents[map_level] {
+                            adjust_count(parent, 1);
+                        }
+                        match installed.last_mut() {
+                            Some(run) if run.end == map_virt => run.end += page_size,
+                            _ => installed.push(map_virt..map_virt + page_size),
+                        }
+                    }
 
                     unsafe { entry_ptr.write_volatile(new_entry) };
 
@@ -247,20 +481,342 @@ impl Hat {
             }
         }
 
-        self.mapped_pages[map_level] += num_pages;
+        Ok(())
+    }
+
+    /// Unlink the empty tables on the path to `virt`, to be freed with `batch`
+    ///
+    /// A failed [`Hat::map_pages()`] may have allocated tables for the translation it was
+    /// inserting, which must not be left linked with nothing in them.
+    fn release_path(
+        &self,
+        parents: &[Option<*mut Pte>; MAX_LEVELS],
+        level: usize,
+        virt: VirtAddr,
+        batch: &mut Batch,
+    ) {
+        let before = batch.empty_tables.len();
+        release_entries(parents, level, 0, &mut batch.empty_tables);
+        let released = batch.empty_tables.len() - before;
+
+        // The processor may have cached the entries referencing the tables.
+        if released > 0 {
+            let size = level_size(level + released);
+            let base = VirtAddr(virt.0 & !(size - 1));
+            batch.add(base..base + size);
+        }
+    }
+
+    /// Insert a translation into the address space, using the largest pages possible
+    ///
+    /// The range is split into runs of 4KiB, 2MiB and 1GiB pages, according to the alignment
+    /// of `virt` and `phys` at each point. Only 4KiB alignment is required.
+    ///
+    /// Fails without mapping anything if any part of the range is already mapped.
+    pub fn map_range(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: usize,
+        prot: Prot,
+        cache: CacheMode,
+    ) -> Result<()> {
+        debug_assert!(virt.is_aligned(PAGE_SIZE));
+        debug_assert!(phys.is_aligned(PAGE_SIZE));
+        debug_assert!(pow2::is_aligned!(size, PAGE_SIZE));
+
+        // The runs are mapped one by one, a conflict found halfway through would leave the
+        // earlier runs mapped.
+        if let Some(mapping) = self.mappings(virt..virt + size).next() {
+            return Err(Error::AlreadyMapped(mapping.phys));
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let page_size = best_page_size(virt + offset, phys + offset, size - offset);
+
+            // Extend the run for as long as the same size is the best choice.
+            let mut run = page_size.size();
+            while offset + run < size
+                && best_page_size(virt + offset + run, phys + offset + run, size - offset - run)
+                    == page_size
+            {
+                run += page_size.size();
+            }
+
+            self.map_pages(virt + offset, phys + offset, run, page_size, prot, cache)?;
+            offset += run;
+        }
 
         Ok(())
     }
 
-    #[inline(always)]
-    pub fn switch_to(&self) {
-        unsafe {
-            asm!(
-                "mov cr3, {}",
-                in(reg) self.cr3,
-                options(nostack, preserves_flags)
+    /// Remove every translation in `range`, whatever the size of its pages
+    ///
+    /// This is the counterpart to [`Hat::map_range()`]. Huge pages which are only partially
+    /// covered by `range` are split first, the parts outside of `range` stay mapped.
+    pub fn unmap_range(&mut self, range: Range<VirtAddr>) {
+        debug_assert!(range.start.is_aligned(PAGE_SIZE));
+        debug_assert!(range.end.is_aligned(PAGE_SIZE));
+
+        if range.is_empty() {
+            return;
+        }
+
+        // Only the pages at either end of the range can extend outside of it.
+        let mut batch = Batch::default();
+        for edge in [range.start.0, range.end.0 - PAGE_SIZE] {
+            loop {
+                let (level, entry_ptr) = self.walk(VirtAddr(edge));
+                let entry = unsafe { entry_ptr.read_volatile() };
+                let size = level_size(level);
+                let base = edge & !(size - 1);
+                let end = base.saturating_add(size);
+                if !entry.present() || base >= range.start.0 && end <= range.end.0 {
+                    break;
+                }
+
+                // Translations of split pages may be cached with the old page size, the whole
+                // page must be flushed.
+                batch.add(VirtAddr(base)..VirtAddr(end));
+                self.split(entry_ptr, level);
+            }
+        }
+
+        let mappings = self.mappings(range).collect::<Vec<_>>();
+        for mapping in mappings {
+            debug_assert!(mapping.virt.is_aligned(mapping.page_size.size()));
+            self.unmap_pages_batched(mapping.virt, mapping.size, mapping.page_size, &mut batch);
+        }
+
+        self.finish(batch);
+    }
+
+    /// Change the protection of every translation in `range`
+    ///
+    /// Huge pages which are only partially covered by `range` are split into smaller pages
+    /// first. Unmapped addresses in the range are skipped.
+    ///
+    /// Present entries always allow reads, so `prot` must grant some access. Pages which
+    /// should not be accessible at all have to be unmapped instead.
+    pub fn protect(&mut self, range: Range<VirtAddr>, prot: Prot) {
+        debug_assert!(range.start.is_aligned(PAGE_SIZE));
+        debug_assert!(range.end.is_aligned(PAGE_SIZE));
+        assert!(
+            prot.intersects(Prot::READ | Prot::WRITE | Prot::EXEC),
+            "protect() cannot revoke all access, unmap the range instead"
+        );
+
+        let prot_mask = PteFlags::WRITE | PteFlags::USER | MMU_INFO.nx_bit;
+        let prot_flags = MMU_INFO.protmap[prot];
+
+        let mut batch = Batch::default();
+        batch.add(range.clone());
+
+        let mut virt = range.start.0;
+        while virt < range.end.0 {
+            let (level, entry_ptr) = self.walk(VirtAddr(virt));
+            let entry = unsafe { entry_ptr.read_volatile() };
+            let size = level_size(level);
+            let base = virt & !(size - 1);
+            let end = base.saturating_add(size);
+
+            if !entry.present() {
+                virt = end;
+                continue;
+            }
+
+            if base < virt || end > range.end.0 {
+                // Translations of split pages may be cached with the old page size, the whole
+                // page must be flushed.
+                batch.add(VirtAddr(base)..VirtAddr(end));
+                self.split(entry_ptr, level);
+                continue;
+            }
+
+            let new_entry = Pte(entry.0 & !prot_mask.bits() | prot_flags.bits());
+            unsafe { entry_ptr.write_volatile(new_entry) };
+            virt = end;
+        }
+
+        self.finish(batch);
+    }
+
+    /// Replace the huge page mapped by the entry at `entry_ptr` with a table of pages of the
+    /// next smaller size, with the same attributes
+    fn split(&mut self, entry_ptr: *mut Pte, level: usize) {
+        debug_assert!(level > 0);
+
+        let entry = unsafe { entry_ptr.read_volatile() };
+        let phys = entry.leaf_addr(level);
+        let child_size = level_size(level - 1);
+
+        // The PAT bit moves down into the address field of huge pages.
+        let pat = entry.flags().contains(PteFlags::PAT_HUGE);
+        let mut flags = entry.flags().difference(PteFlags::HUGE | PteFlags::PAT_HUGE);
+        if level - 1 == 0 {
+            flags.set(PteFlags::PAT_4K, pat);
+        } else {
+            flags |= PteFlags::HUGE;
+            flags.set(PteFlags::PAT_HUGE, pat);
+        }
+
+        let page = alloc_page_table();
+        let table = page.addr.to_virt().as_mut_ptr::<Pte>();
+        for i in 0..PTES_PER_TABLE {
+            unsafe { table.add(i).write(Pte::new(phys + i * child_size, flags)) };
+        }
+
+        let parent = Pte::new(page.addr, PARENT_FLAGS | PteFlags::PRESENT);
+        unsafe { entry_ptr.write_volatile(parent.with_count(PTES_PER_TABLE)) };
+
+        self.mapped_pages[level] -= 1;
+        self.mapped_pages[level - 1] += PTES_PER_TABLE;
+    }
+
+    /// Walk the page tables to the entry which translates `virt`
+    ///
+    /// Returns the level of the last entry visited and a pointer to it. The entry is either
+    /// not present or a leaf.
+    fn walk(&self, virt: VirtAddr) -> (usize, *mut Pte) {
+        let mut table = self.top_level.addr.to_virt().as_mut_ptr::<Pte>();
+        let mut level = MMU_INFO.max_level;
+
+        loop {
+            let entry_ptr = unsafe { table.add(virt.index_for(level)) };
+            let entry = unsafe { entry_ptr.read_volatile() };
+
+            if level == 0 || !entry.present() || entry.huge() {
+                return (level as usize, entry_ptr);
+            }
+
+            table = entry.addr().to_virt().as_mut_ptr();
+            level -= 1;
+        }
+    }
+
+    /// Returns the physical address `virt` translates to, with the size and protection of
+    /// the page containing it
+    pub fn translate(&self, virt: VirtAddr) -> Option<(PhysAddr, PageSize, Prot)> {
+        if !virt.is_canonical() {
+            return None;
+        }
+
+        let (level, entry_ptr) = self.walk(virt);
+        let entry = unsafe { entry_ptr.read_volatile() };
+        if !entry.present() {
+            return None;
+        }
+
+        let offset = virt.0 & (level_size(level) - 1);
+        Some((
+            entry.leaf_addr(level) + offset,
+            PageSize::from_level(level),
+            entry.prot(),
+        ))
+    }
+
+    /// Returns an iterator over the translations in `range`
+    ///
+    /// Adjacent pages are combined into a single [`Mapping`] when they are physically
+    /// contiguous, the same size and have the same protection.
+    pub fn mappings(&self, range: Range<VirtAddr>) -> Mappings<'_> {
+        Mappings {
+            hat:  self,
+            next: range.start.0,
+            end:  range.end.0,
+        }
+    }
+
+    /// Test and clear the usage bits of every page in `range`
+    ///
+    /// Returns the pages which had any usage bit set, only the bits in `clear` are cleared.
+    /// Huge pages which are partially covered by `range` are reported and cleared as a whole.
+    /// The TLB is flushed of every page whose bits were cleared before returning, so the
+    /// processor sets them again on the next access.
+    pub fn test_and_clear(&mut self, range: Range<VirtAddr>, clear: PageUsage) -> Vec<TouchedPage> {
+        let clear_mask = PteFlags::from(clear);
+        let mut touched = Vec::new();
+        let mut flush: Option<Range<VirtAddr>> = None;
+
+        let mut virt = range.start.0;
+        while virt < range.end.0 {
+            if !VirtAddr(virt).is_canonical() {
+                virt = MMU_INFO.noncanonical_hole.end.0;
+                continue;
+            }
+
+            let (level, entry_ptr) = self.walk(VirtAddr(virt));
+            let size = level_size(level);
+            let base = virt & !(size - 1);
+            let end = base.saturating_add(size);
+            virt = end;
+
+            if !unsafe { entry_ptr.read_volatile() }.present() {
+                continue;
+            }
+
+            // The processor sets the usage bits without taking any locks, they must be cleared
+            // atomically or its updates may be lost.
+            let entry = unsafe { &*entry_ptr.cast::<AtomicU64>() };
+            let entry = Pte(entry.fetch_and(!clear_mask.bits(), Ordering::Relaxed));
+            let usage = entry.usage();
+            if usage.is_empty() {
+                continue;
+            }
+
+            if usage.intersects(clear) {
+                flush.get_or_insert(VirtAddr(base)..VirtAddr(end)).end = VirtAddr(end);
+            }
+            touched.push(TouchedPage {
+                virt: VirtAddr(base),
+                phys: entry.leaf_addr(level),
+                page_size: PageSize::from_level(level),
+                usage,
+            });
+        }
+
+        if let Some(flush) = flush {
+            self.flush(flush);
+        }
+        touched
+    }
+
+    /// Log every translation in the address space
+    pub fn dump(&self) {
+        log::info!("address space {:p}:", self.top_level.addr);
+        for mapping in self.mappings(VirtAddr(0)..VirtAddr(usize::MAX)) {
+            log::info!(
+                "  {:p}-{:p} -> {:p} {} {:?} ({} pages)",
+                mapping.virt,
+                mapping.virt + mapping.size,
+                mapping.phys,
+                mapping.page_size,
+                mapping.prot,
+                mapping.size / mapping.page_size.size(),
             );
         }
+        log::info!(
+            "  {} 4K, {} 2M, {} 1G pages mapped",
+            self.mapped_pages[0],
+            self.mapped_pages[1],
+            self.mapped_pages[2],
+        );
+    }
+
+    /// Make this the current address space
+    ///
+    /// With PCIDs enabled, switching back to an address space which still has a PCID on this
+    /// CPU keeps its cached translations.
+    pub fn switch_to(&self) {
+        let token = trap::disable();
+        self.active.insert(unsafe { (*this_cpu()).cpu_id });
+        let switched = unsafe { tlb::switch_to(self.top_level.addr, self.generation) };
+        // Only user code can leave branch predictions behind for the next user to trip on.
+        if switched && self.user {
+            spec::switch_address_space();
+        }
+        trap::enable(token);
     }
 }
 
@@ -280,6 +836,11 @@ const PAGE_SIZE_1G: usize = 1usize << PAGE_SHIFT_1G;
 
 const PARENT_FLAGS: PteFlags = PteFlags::USER.union(PteFlags::WRITE);
 
+/// Returns `true` if PCIDs are enabled
+pub(super) fn pcid_enabled() -> bool {
+    MMU_INFO.pcide
+}
+
 struct MmuInfo {
     nx_bit:            PteFlags,
     global_bit:        PteFlags,
@@ -411,7 +972,7 @@ pub fn init() {
         }
     }
 
-    KERNEL_HAT.initialize_with(Hat::new(1));
+    KERNEL_HAT.initialize_with(Hat::alloc(false));
 }
 
 struct ProtMap([PteFlags; 16]);
@@ -466,6 +1027,123 @@ fn alloc_page_table() -> &'static vm::Page {
     page
 }
 
+/// Return a page table to the pmap queue
+fn free_page_table(addr: PhysAddr) {
+    vm::Page::free(vm::Page::from_addr(addr), &mut PMAP_QUEUE.lock());
+}
+
+/// Free the table at `addr`, containing entries at `level`, and every table below it
+fn free_tables(addr: PhysAddr, level: usize) {
+    if level > 0 {
+        let table = addr.to_virt().as_ptr::<Pte>();
+        for i in 0..PTES_PER_TABLE {
+            let entry = unsafe { table.add(i).read_volatile() };
+            if entry.present() && !entry.huge() {
+                free_tables(entry.addr(), level - 1);
+            }
+        }
+    }
+    free_page_table(addr);
+}
+
+/// Invalidations collected over one operation on a [`Hat`], to be performed with a single
+/// shootdown by [`Hat::finish()`]
+#[derive(Default)]
+struct Batch {
+    /// Range covering every translation which was changed
+    range:        Option<Range<VirtAddr>>,
+    /// Tables unlinked from the address space, freed after the shootdown
+    empty_tables: Vec<PhysAddr>,
+}
+
+impl Batch {
+    fn add(&mut self, range: Range<VirtAddr>) {
+        let batch = self.range.get_or_insert(range.clone());
+        batch.start = cmp::min(batch.start, range.start);
+        batch.end = cmp::max(batch.end, range.end);
+    }
+}
+
+/// Returns `entry_ptr` if it keeps the occupancy count of the table it references
+///
+/// The top-level entries of the kernel half are copied into every address space, so their
+/// counts could not be kept consistent. Those tables are never freed.
+fn tracked_parent(entry_ptr: *mut Pte, level: u32, virt: VirtAddr) -> Option<*mut Pte> {
+    let shared = level == MMU_INFO.max_level && virt >= MMU_INFO.noncanonical_hole.end;
+    (!shared).then_some(entry_ptr)
+}
+
+/// Add `delta` to the occupancy count kept in `parent`, returning the new count
+fn adjust_count(parent: *mut Pte, delta: isize) -> usize {
+    unsafe {
+        let entry = parent.read_volatile();
+        let count = entry.count().checked_add_signed(delta).unwrap();
+        parent.write_volatile(entry.with_count(count));
+        count
+    }
+}
+
+/// Account for `removed` entries cleared from the table at `level`
+///
+/// Tables left empty are unlinked from their parents, which may leave those empty in turn,
+/// and collected into `empty_tables` to be freed after the TLB has been flushed.
+fn release_entries(
+    parents: &[Option<*mut Pte>; MAX_LEVELS],
+    mut level: usize,
+    mut removed: usize,
+    empty_tables: &mut Vec<PhysAddr>,
+) {
+    while let Some(parent) = parents.get(level).copied().flatten() {
+        if adjust_count(parent, -(removed as isize)) != 0 {
+            break;
+        }
+
+        let table = unsafe { parent.read_volatile() }.addr();
+        unsafe { parent.write_volatile(Pte::NULL) };
+        empty_tables.push(table);
+
+        removed = 1;
+        level += 1;
+    }
+}
+
+/// Returns the largest page size which can map `size` bytes at `virt` to `phys`
+fn best_page_size(virt: VirtAddr, phys: PhysAddr, size: usize) -> PageSize {
+    let fits = |page_size: PageSize| {
+        let page_size = page_size.size();
+        size >= page_size && virt.is_aligned(page_size) && phys.is_aligned(page_size)
+    };
+
+    if MMU_INFO.gigapages && fits(PageSize::Size1GiB) {
+        PageSize::Size1GiB
+    } else if fits(PageSize::Size2MiB) {
+        PageSize::Size2MiB
+    } else {
+        PageSize::Size4KiB
+    }
+}
+
+/// Returns the flags selecting the PAT entry for `cache` in an entry at `level`
+fn cache_flags(cache: CacheMode, level: usize) -> PteFlags {
+    let index = cache.pat_index();
+    let mut flags = PteFlags::empty();
+    if index & 1 != 0 {
+        flags |= PteFlags::CACHE_WRITE_THROUGH;
+    }
+    if index & 2 != 0 {
+        flags |= PteFlags::CACHE_DISABLE;
+    }
+    if index & 4 != 0 {
+        // Bit 7 is the PAT bit in 4K entries, but selects a huge page in the others.
+        flags |= if level == 0 {
+            PteFlags::PAT_4K
+        } else {
+            PteFlags::PAT_HUGE
+        };
+    }
+    flags
+}
+
 static PROT_MAP: Lazy<ProtMap> = Lazy::new(|| {
     let nx_bit = if cpu::CPU_FEATURES[CpuFeat::EXECUTE_DISABLE] {
         PteFlags::NO_EXECUTE
@@ -500,6 +1178,15 @@ impl From<Prot> for PteFlags {
     }
 }
 
+impl From<PageUsage> for PteFlags {
+    fn from(usage: PageUsage) -> Self {
+        let mut flags = PteFlags::empty();
+        flags.set(PteFlags::ACCESSED, usage.contains(PageUsage::ACCESSED));
+        flags.set(PteFlags::DIRTY, usage.contains(PageUsage::DIRTY));
+        flags
+    }
+}
+
 #[repr(transparent)]
 #[derive(Clone, Copy, Eq, PartialEq)]
 struct Pte(u64);
@@ -509,6 +1196,11 @@ impl Pte {
 
     const ADDR_MASK: u64 = 0x000ffffffffff000;
 
+    /// Entries which reference a table keep the number of present entries in that table in
+    /// bits the processor ignores.
+    const COUNT_SHIFT: u32 = 52;
+    const COUNT_MASK: u64 = 0x3ff << Self::COUNT_SHIFT;
+
     const fn new(addr: PhysAddr, flags: PteFlags) -> Pte {
         Self(addr.0 as u64 | flags.bits())
     }
@@ -528,6 +1220,44 @@ impl Pte {
     const fn huge(self) -> bool {
         self.flags().contains(PteFlags::HUGE)
     }
+
+    /// Returns the number of present entries in the table referenced by this entry
+    const fn count(self) -> usize {
+        ((self.0 & Self::COUNT_MASK) >> Self::COUNT_SHIFT) as usize
+    }
+
+    const fn with_count(self, count: usize) -> Pte {
+        Pte(self.0 & !Self::COUNT_MASK | (count as u64) << Self::COUNT_SHIFT)
+    }
+
+    /// Returns the address of the page mapped by a leaf entry at `level`
+    ///
+    /// The PAT bit of huge pages is within the address field, so it must be masked off.
+    const fn leaf_addr(self, level: usize) -> PhysAddr {
+        PhysAddr::new(self.addr().0 & !(level_size(level) - 1))
+    }
+
+    /// Returns the protection of a leaf entry
+    fn prot(self) -> Prot {
+        let mask = PteFlags::WRITE | PteFlags::USER | MMU_INFO.nx_bit;
+        let flags = self.flags() & mask;
+
+        // Prefer the most permissive match, entries don't distinguish readable from
+        // unreadable pages.
+        (0..16)
+            .rev()
+            .map(Prot::from_bits_truncate)
+            .find(|&prot| MMU_INFO.protmap[prot] & mask == flags)
+            .unwrap_or(Prot::empty())
+    }
+
+    /// Returns the usage bits of a leaf entry
+    fn usage(self) -> PageUsage {
+        let mut usage = PageUsage::empty();
+        usage.set(PageUsage::ACCESSED, self.flags().contains(PteFlags::ACCESSED));
+        usage.set(PageUsage::DIRTY, self.flags().contains(PteFlags::DIRTY));
+        usage
+    }
 }
 
 impl fmt::Debug for Pte {
@@ -541,3 +1271,229 @@ impl ops::BitOrAssign<PteFlags> for Pte {
         self.0 |= rhs.bits();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start of the user address range the tests map, at a 1GiB boundary
+    const VIRT: VirtAddr = VirtAddr(1 << 39);
+    /// Physical memory the tests map, it is never accessed
+    const PHYS: PhysAddr = PhysAddr(1 << 32);
+
+    const SIZE_4K: usize = level_size(0);
+    const SIZE_2M: usize = level_size(1);
+    const SIZE_1G: usize = level_size(2);
+
+    const RW: Prot = Prot::READ.union(Prot::WRITE);
+    const WB: CacheMode = CacheMode::WriteBack;
+
+    crate::test_cases!(
+        mappings_coalesce,
+        protect_split,
+        unmap_frees_tables,
+        map_conflict,
+        map_range,
+        unmap_range_split,
+    );
+
+    /// Returns the runs in `range` as `(virt offset, phys offset, size, page size)`, relative
+    /// to [`VIRT`] and [`PHYS`]
+    fn runs(hat: &Hat, range: Range<VirtAddr>) -> Vec<(usize, usize, usize, PageSize)> {
+        hat.mappings(range)
+            .map(|m| (m.virt.0 - VIRT.0, m.phys.0 - PHYS.0, m.size, m.page_size))
+            .collect()
+    }
+
+    /// Map pages of mixed sizes, then translate and iterate over them
+    fn mappings_coalesce() {
+        let hat = Hat::new();
+        let mut hat = hat.lock();
+
+        // Two 4KiB pages, two 2MiB pages and a 4KiB page, which is read-only.
+        let pages = [
+            (SIZE_2M - 2 * SIZE_4K, 2 * SIZE_4K, PageSize::Size4KiB, RW),
+            (SIZE_2M, 2 * SIZE_2M, PageSize::Size2MiB, RW),
+            (3 * SIZE_2M, SIZE_4K, PageSize::Size4KiB, Prot::READ),
+        ];
+        for (offset, size, page_size, prot) in pages {
+            hat.map_pages(VIRT + offset, PHYS + offset, size, page_size, prot, WB)
+                .unwrap();
+        }
+
+        let all = VIRT..VIRT + 4 * SIZE_2M;
+        assert_eq!(runs(&hat, all.clone()), [
+            (SIZE_2M - 2 * SIZE_4K, SIZE_2M - 2 * SIZE_4K, 2 * SIZE_4K, PageSize::Size4KiB),
+            (SIZE_2M, SIZE_2M, 2 * SIZE_2M, PageSize::Size2MiB),
+            (3 * SIZE_2M, 3 * SIZE_2M, SIZE_4K, PageSize::Size4KiB),
+        ]);
+
+        // Runs are clipped to the range.
+        let range = VIRT + SIZE_2M - SIZE_4K..VIRT + SIZE_2M + SIZE_4K;
+        assert_eq!(runs(&hat, range), [
+            (SIZE_2M - SIZE_4K, SIZE_2M - SIZE_4K, SIZE_4K, PageSize::Size4KiB),
+            (SIZE_2M, SIZE_2M, SIZE_4K, PageSize::Size2MiB),
+        ]);
+
+        for (offset, page_size, prot) in [
+            (SIZE_2M - SIZE_4K + 0x123, PageSize::Size4KiB, RW),
+            (SIZE_2M + 0x4567, PageSize::Size2MiB, RW),
+            (3 * SIZE_2M - 0x89ab, PageSize::Size2MiB, RW),
+            (3 * SIZE_2M + 0xcde, PageSize::Size4KiB, Prot::READ),
+        ] {
+            assert_eq!(
+                hat.translate(VIRT + offset),
+                Some((PHYS + offset, page_size, prot))
+            );
+        }
+        assert_eq!(hat.translate(VIRT + SIZE_2M - 3 * SIZE_4K), None);
+        assert_eq!(hat.translate(VIRT + 3 * SIZE_2M + SIZE_4K), None);
+
+        for (offset, size, page_size, _) in pages {
+            hat.unmap_pages(VIRT + offset, size, page_size);
+        }
+        assert!(runs(&hat, all).is_empty());
+    }
+
+    /// Protect part of a 2MiB page, which must be split into 4KiB pages
+    fn protect_split() {
+        let hat = Hat::new();
+        let mut hat = hat.lock();
+
+        hat.map_pages(VIRT, PHYS, SIZE_2M, PageSize::Size2MiB, RW, WB)
+            .unwrap();
+        hat.protect(VIRT + SIZE_4K..VIRT + 2 * SIZE_4K, Prot::READ);
+
+        assert_eq!(runs(&hat, VIRT..VIRT + SIZE_2M), [
+            (0, 0, SIZE_4K, PageSize::Size4KiB),
+            (SIZE_4K, SIZE_4K, SIZE_4K, PageSize::Size4KiB),
+            (2 * SIZE_4K, 2 * SIZE_4K, SIZE_2M - 2 * SIZE_4K, PageSize::Size4KiB),
+        ]);
+        assert_eq!(hat.translate(VIRT).unwrap().2, RW);
+        assert_eq!(hat.translate(VIRT + SIZE_4K).unwrap().2, Prot::READ);
+        assert_eq!(hat.translate(VIRT + 2 * SIZE_4K).unwrap().2, RW);
+        assert_eq!(hat.mapped_pages[0], PTES_PER_TABLE);
+        assert_eq!(hat.mapped_pages[1], 0);
+
+        hat.unmap_pages(VIRT, SIZE_2M, PageSize::Size4KiB);
+        assert_eq!(hat.mapped_pages, [0; MAX_PAGE_LEVEL + 1]);
+    }
+
+    /// Unmap pages until their tables are empty, which must unlink them up to the top level
+    fn unmap_frees_tables() {
+        let hat = Hat::new();
+        let mut hat = hat.lock();
+
+        // Two pages in different leaf tables, sharing every table above them.
+        let other = VIRT + SIZE_2M;
+        hat.map_pages(VIRT, PHYS, SIZE_4K, PageSize::Size4KiB, RW, WB)
+            .unwrap();
+        hat.map_pages(other, PHYS, SIZE_4K, PageSize::Size4KiB, RW, WB)
+            .unwrap();
+
+        // Only the leaf table of the first page is empty.
+        hat.unmap_pages(VIRT, SIZE_4K, PageSize::Size4KiB);
+        let (level, entry_ptr) = hat.walk(VIRT);
+        assert_eq!(level, 1);
+        assert!(!unsafe { entry_ptr.read_volatile() }.present());
+        assert!(hat.translate(other).is_some());
+
+        // Now every table below the top level is.
+        hat.unmap_pages(other, SIZE_4K, PageSize::Size4KiB);
+        let (level, entry_ptr) = hat.walk(VIRT);
+        assert_eq!(level, MMU_INFO.max_level as usize);
+        assert!(!unsafe { entry_ptr.read_volatile() }.present());
+        assert_eq!(hat.mapped_pages, [0; MAX_PAGE_LEVEL + 1]);
+    }
+
+    /// Map pages over a conflicting translation, which must leave nothing behind
+    fn map_conflict() {
+        let hat = Hat::new();
+        let mut hat = hat.lock();
+
+        // The second page is in another leaf table, the first page's table is allocated by
+        // the failing call.
+        let virt = VIRT + SIZE_2M - SIZE_4K;
+        hat.map_pages(VIRT + SIZE_2M, PHYS, SIZE_4K, PageSize::Size4KiB, RW, WB)
+            .unwrap();
+        let result = hat.map_pages(virt, PHYS + SIZE_2M, 2 * SIZE_4K, PageSize::Size4KiB, RW, WB);
+        assert!(matches!(result, Err(Error::AlreadyMapped(_))));
+
+        assert_eq!(hat.translate(virt), None);
+        let (level, entry_ptr) = hat.walk(virt);
+        assert_eq!(level, 1);
+        assert!(!unsafe { entry_ptr.read_volatile() }.present());
+        assert_eq!(
+            hat.translate(VIRT + SIZE_2M),
+            Some((PHYS, PageSize::Size4KiB, RW))
+        );
+        assert_eq!(hat.mapped_pages[0], 1);
+
+        hat.unmap_pages(VIRT + SIZE_2M, SIZE_4K, PageSize::Size4KiB);
+    }
+
+    /// Map a range which needs every page size, then translate and iterate over it
+    fn map_range() {
+        let hat = Hat::new();
+        let mut hat = hat.lock();
+
+        // Two 4KiB pages, a 2MiB page, a 1GiB page, a 2MiB page and a 4KiB page.
+        let start = SIZE_1G - SIZE_2M - 2 * SIZE_4K;
+        let end = 2 * SIZE_1G + SIZE_2M + SIZE_4K;
+        hat.map_range(VIRT + start, PHYS + start, end - start, RW, WB)
+            .unwrap();
+
+        let huge = if MMU_INFO.gigapages {
+            PageSize::Size1GiB
+        } else {
+            PageSize::Size2MiB
+        };
+        let mut expected = vec![(start, start, 2 * SIZE_4K, PageSize::Size4KiB)];
+        let mut offset = start + 2 * SIZE_4K;
+        for (size, page_size) in [
+            (SIZE_2M, PageSize::Size2MiB),
+            (SIZE_1G, huge),
+            (SIZE_2M, PageSize::Size2MiB),
+            (SIZE_4K, PageSize::Size4KiB),
+        ] {
+            // Adjacent runs of the same page size are combined.
+            match expected.last_mut() {
+                Some(last) if last.3 == page_size => last.2 += size,
+                _ => expected.push((offset, offset, size, page_size)),
+            }
+            offset += size;
+        }
+        assert_eq!(runs(&hat, VIRT + start..VIRT + end), expected);
+        assert_eq!(
+            hat.translate(VIRT + SIZE_1G + 0x89ab),
+            Some((PHYS + SIZE_1G + 0x89ab, huge, RW))
+        );
+
+        // Nothing is mapped when part of the range is already mapped.
+        let virt = VIRT + start - SIZE_4K;
+        let result = hat.map_range(virt, PHYS, 2 * SIZE_4K, RW, WB);
+        assert!(matches!(result, Err(Error::AlreadyMapped(_))));
+        assert_eq!(hat.translate(virt), None);
+
+        hat.unmap_range(VIRT + start..VIRT + end);
+        assert!(runs(&hat, VIRT + start..VIRT + end).is_empty());
+        assert_eq!(hat.mapped_pages, [0; MAX_PAGE_LEVEL + 1]);
+    }
+
+    /// Unmap a range which ends inside of a 2MiB page, the rest of which must stay mapped
+    fn unmap_range_split() {
+        let hat = Hat::new();
+        let mut hat = hat.lock();
+
+        hat.map_range(VIRT, PHYS, 2 * SIZE_2M, RW, WB).unwrap();
+        hat.unmap_range(VIRT + SIZE_4K..VIRT + SIZE_2M + SIZE_4K);
+
+        assert_eq!(runs(&hat, VIRT..VIRT + 2 * SIZE_2M), [
+            (0, 0, SIZE_4K, PageSize::Size4KiB),
+            (SIZE_2M + SIZE_4K, SIZE_2M + SIZE_4K, SIZE_2M - SIZE_4K, PageSize::Size4KiB),
+        ]);
+
+        hat.unmap_range(VIRT..VIRT + 2 * SIZE_2M);
+        assert_eq!(hat.mapped_pages, [0; MAX_PAGE_LEVEL + 1]);
+    }
+}
@@ -0,0 +1,305 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! I/O APIC
+//!
+//! I/O APICs are discovered through the MADT and registered with the generic interrupt layer
+//! as [`IrqChip`]s. Each interrupt is delivered on a dynamically allocated vector, which is
+//! translated back into the GSI when it arrives.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{
+    lapic,
+    memtype::{self, CacheMode},
+    pio::outb,
+    trap::{self, TrapFrame},
+};
+use crate::{
+    cpu::CpuRef,
+    drivers::acpi::madt::{self, Madt, MadtEntry},
+    intr::{self, IrqChip, IsaRoute, Polarity, Trigger},
+    sync::{mutex::MutexKind, Mutex},
+    vm::PhysAddr,
+};
+
+/// Size of the register window
+const MMIO_SIZE: usize = 0x20;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIR_POLARITY_LOW: u64 = 1 << 13;
+const REDIR_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DEST_SHIFT: u32 = 56;
+
+/// GSI delivered on each vector, or `!0` if the vector does not belong to an I/O APIC
+static VECTOR_GSI: [AtomicU32; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicU32 = AtomicU32::new(!0);
+    [NONE; 256]
+};
+
+/// The indirect register window of an I/O APIC
+struct Registers {
+    base: *mut u32,
+}
+
+unsafe impl Send for Registers {}
+
+impl Registers {
+    unsafe fn read(&mut self, reg: u32) -> u32 {
+        self.base.write_volatile(reg);
+        self.base.add(4).read_volatile()
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        self.base.write_volatile(reg);
+        self.base.add(4).write_volatile(value);
+    }
+
+    unsafe fn read_redirection(&mut self, index: u32) -> u64 {
+        let reg = REG_REDIRECTION_TABLE + 2 * index;
+        let lo = self.read(reg) as u64;
+        let hi = self.read(reg + 1) as u64;
+        hi << 32 | lo
+    }
+
+    unsafe fn write_redirection(&mut self, index: u32, entry: u64) {
+        // Write the high half first so the entry is never live with a stale destination.
+        let reg = REG_REDIRECTION_TABLE + 2 * index;
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+pub struct IoApic {
+    id:          u8,
+    gsi_base:    u32,
+    num_entries: u32,
+    regs:        Mutex<Registers>,
+}
+
+impl IoApic {
+    unsafe fn new(id: u8, address: PhysAddr, gsi_base: u32) -> IoApic {
+        let mut regs = Registers {
+            base: memtype::map_device(address, MMIO_SIZE, CacheMode::Uncached).as_mut_ptr(),
+        };
+        let num_entries = (regs.read(REG_VERSION) >> 16 & 0xff) + 1;
+
+        // Start with everything masked, firmware may have left entries enabled.
+        for index in 0..num_entries {
+            regs.write_redirection(index, REDIR_MASKED);
+        }
+
+        log::info!(
+            "ioapic{id}: apic id {}, gsi {}..{}",
+            regs.read(REG_ID) >> 24 & 0xf,
+            gsi_base,
+            gsi_base + num_entries
+        );
+
+        Self {
+            id,
+            gsi_base,
+            num_entries,
+            regs: Mutex::new(MutexKind::Spin, regs),
+        }
+    }
+
+    fn modify(&self, irq: u32, f: impl FnOnce(u64) -> u64) {
+        let index = irq - self.gsi_base;
+
+        // Spurious interrupts are masked from interrupt context, which must not find the
+        // registers locked by the code it interrupted.
+        let token = crate::trap::disable();
+        let mut regs = self.regs.lock();
+        unsafe {
+            let entry = regs.read_redirection(index);
+            regs.write_redirection(index, f(entry));
+        }
+        drop(regs);
+        crate::trap::enable(token);
+    }
+}
+
+impl IrqChip for IoApic {
+    fn name(&self) -> &str {
+        "ioapic"
+    }
+
+    fn handles(&self, irq: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.num_entries).contains(&irq)
+    }
+
+    fn setup(&self, irq: u32, trigger: Trigger, polarity: Polarity) -> intr::Result<()> {
+        // See `retarget`, physical destination mode only reaches the first 256 APIC IDs.
+        let apic_id = lapic::id();
+        if apic_id >= 256 {
+            log::warn!(
+                "ioapic{}: gsi {irq} cannot be delivered to apic id {apic_id}, leaving it",
+                self.id
+            );
+            return Err(intr::Error::Unreachable(irq));
+        }
+
+        let vector = trap::alloc_vector(interrupt).ok_or(intr::Error::NoVectors)?;
+        VECTOR_GSI[vector as usize].store(irq, Ordering::Relaxed);
+
+        let mut entry = REDIR_MASKED | vector as u64;
+        if trigger == Trigger::Level {
+            entry |= REDIR_TRIGGER_LEVEL;
+        }
+        if polarity == Polarity::Low {
+            entry |= REDIR_POLARITY_LOW;
+        }
+        entry |= (apic_id as u64) << REDIR_DEST_SHIFT;
+
+        self.modify(irq, |old| {
+            // Release the vector of any previous configuration.
+            let old_vector = (old & 0xff) as u8;
+            if old_vector != 0 && VECTOR_GSI[old_vector as usize].load(Ordering::Relaxed) == irq {
+                VECTOR_GSI[old_vector as usize].store(!0, Ordering::Relaxed);
+                trap::free_vector(old_vector);
+            }
+            entry
+        });
+
+        log::debug!(
+            "ioapic{}: gsi {irq} -> vector {vector:#x}, {trigger:?}, active {polarity:?}",
+            self.id
+        );
+
+        Ok(())
+    }
+
+    fn mask(&self, irq: u32) {
+        self.modify(irq, |entry| entry | REDIR_MASKED);
+    }
+
+    fn unmask(&self, irq: u32) {
+        self.modify(irq, |entry| entry & !REDIR_MASKED);
+    }
+
+    fn retarget(&self, irq: u32, cpu: CpuRef) -> intr::Result<()> {
+        // Physical destination mode only reaches the first 256 APIC IDs. Anything above that
+        // requires interrupt remapping.
+        let apic_id = cpu.identity.apic_id();
+        if apic_id >= 256 {
+            log::warn!(
+                "ioapic{}: gsi {irq} cannot be delivered to apic id {apic_id}, leaving it",
+                self.id
+            );
+            return Err(intr::Error::Unreachable(irq));
+        }
+
+        self.modify(irq, |entry| {
+            entry & !(0xff << REDIR_DEST_SHIFT) | (apic_id as u64) << REDIR_DEST_SHIFT
+        });
+        Ok(())
+    }
+}
+
+fn interrupt(frame: &mut TrapFrame) {
+    let gsi = VECTOR_GSI[frame.vector].load(Ordering::Relaxed);
+    if gsi != !0 {
+        intr::handle(gsi);
+    }
+}
+
+/// Remap and mask the legacy 8259 PICs
+///
+/// Even when masked, the PICs can raise spurious interrupts, so they are moved out of the
+/// way of the exception vectors first.
+unsafe fn disable_legacy_pic() {
+    const PIC1_COMMAND: u16 = 0x20;
+    const PIC1_DATA: u16 = 0x21;
+    const PIC2_COMMAND: u16 = 0xa0;
+    const PIC2_DATA: u16 = 0xa1;
+
+    // ICW1: begin initialization, expect ICW4.
+    outb(PIC1_COMMAND, 0x11);
+    outb(PIC2_COMMAND, 0x11);
+    // ICW2: vector offsets.
+    outb(PIC1_DATA, 0x20);
+    outb(PIC2_DATA, 0x28);
+    // ICW3: the secondary PIC is cascaded on IRQ2.
+    outb(PIC1_DATA, 1 << 2);
+    outb(PIC2_DATA, 2);
+    // ICW4: 8086 mode.
+    outb(PIC1_DATA, 0x01);
+    outb(PIC2_DATA, 0x01);
+
+    // Mask everything.
+    outb(PIC1_DATA, 0xff);
+    outb(PIC2_DATA, 0xff);
+}
+
+/// Discover the system's I/O APICs and interrupt source overrides
+pub fn init() {
+    let Some(madt) = Madt::get() else {
+        log::warn!("ioapic: no MADT, device interrupts are unavailable");
+        return;
+    };
+
+    if madt.flags & madt::PCAT_COMPAT != 0 {
+        unsafe { disable_legacy_pic() };
+    }
+
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic {
+                id,
+                address,
+                gsi_base,
+            } => {
+                let ioapic = Box::leak(Box::new(unsafe { IoApic::new(id, address, gsi_base) }));
+                intr::register_chip(ioapic);
+            }
+            MadtEntry::InterruptOverride {
+                bus: 0,
+                source,
+                gsi,
+                flags,
+            } => {
+                intr::set_isa_route(source, IsaRoute {
+                    irq:      gsi,
+                    trigger:  madt::inti_trigger(flags).unwrap_or(Trigger::Edge),
+                    polarity: madt::inti_polarity(flags).unwrap_or(Polarity::High),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    super::smp::check_madt(&madt);
+}
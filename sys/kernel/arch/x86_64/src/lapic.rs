@@ -0,0 +1,507 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Local APIC
+//!
+//! The x2APIC interface is used whenever the processor supports it, otherwise the registers
+//! are accessed through the xAPIC MMIO window. Register offsets are given as their offset in
+//! the MMIO window; the corresponding x2APIC MSR is `0x800 + (offset >> 4)`.
+//!
+//! The timer is always programmed for a single event, in TSC-deadline mode when supported and
+//! one-shot mode otherwise. Periodic events are emulated by rearming the timer from its
+//! interrupt, which lets them share the hardware with housekeeping hooks.
+
+use core::{
+    cmp,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use cpu_features::CpuFeat;
+
+use super::{
+    cpu::CPU_FEATURES,
+    msr::{self, ApicBase, Msr},
+    pit,
+    trap::{register_handler, TrapFrame},
+    tsc,
+};
+use crate::{cpu, trap, vm::PhysAddr};
+
+/// Vector of the Local APIC timer interrupt
+pub const VECTOR_TIMER: u8 = 0xf0;
+/// Vector of the Local APIC error interrupt
+pub const VECTOR_ERROR: u8 = 0xfe;
+/// Vector of the spurious interrupt
+///
+/// The low 4 bits must be set on processors older than the P6 family.
+pub const VECTOR_SPURIOUS: u8 = 0xff;
+
+const REG_ID: u32 = 0x020;
+const REG_VERSION: u32 = 0x030;
+const REG_TPR: u32 = 0x080;
+const REG_EOI: u32 = 0x0b0;
+const REG_SVR: u32 = 0x0f0;
+const REG_ESR: u32 = 0x280;
+const REG_ICR_LO: u32 = 0x300;
+const REG_ICR_HI: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_THERMAL: u32 = 0x330;
+const REG_LVT_PMI: u32 = 0x340;
+const REG_LVT_LINT0: u32 = 0x350;
+const REG_LVT_LINT1: u32 = 0x360;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INIT: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const SVR_ENABLE: u32 = 1 << 8;
+
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_ONESHOT: u32 = 0b00 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// Divide the timer's input clock by 16
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_SELF: u32 = 0b01 << 18;
+const ICR_SHORTHAND_ALL: u32 = 0b10 << 18;
+const ICR_SHORTHAND_OTHERS: u32 = 0b11 << 18;
+
+static X2APIC: AtomicBool = AtomicBool::new(false);
+static XAPIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Frequency of the timer (after division), in Hz
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read(reg: u32) -> u32 {
+    if X2APIC.load(Ordering::Relaxed) {
+        msr::rdmsr(X2APIC_MSR_BASE + (reg >> 4)) as u32
+    } else {
+        let base = XAPIC_BASE.load(Ordering::Relaxed) as *const u32;
+        base.add(reg as usize / 4).read_volatile()
+    }
+}
+
+unsafe fn write(reg: u32, value: u32) {
+    if X2APIC.load(Ordering::Relaxed) {
+        msr::wrmsr(X2APIC_MSR_BASE + (reg >> 4), value as u64);
+    } else {
+        let base = XAPIC_BASE.load(Ordering::Relaxed) as *mut u32;
+        base.add(reg as usize / 4).write_volatile(value);
+    }
+}
+
+/// Enable and initialize the Local APIC of the current CPU
+///
+/// All local interrupt sources are masked, except LINT1 which is wired to NMI on every
+/// PC-compatible platform.
+pub unsafe fn init() {
+    let mut apic_base = ApicBase::read() | ApicBase::ENABLE;
+
+    if CPU_FEATURES[CpuFeat::X2APIC] {
+        X2APIC.store(true, Ordering::Relaxed);
+        apic_base |= ApicBase::X2APIC;
+    } else {
+        let base = PhysAddr(apic_base.base() as usize);
+        XAPIC_BASE.store(base.to_virt().0, Ordering::Relaxed);
+    }
+
+    apic_base.write();
+
+    register_handler(VECTOR_SPURIOUS, |_| {});
+    register_handler(VECTOR_ERROR, error_interrupt);
+    register_handler(VECTOR_TIMER, timer_interrupt);
+
+    write(REG_TPR, 0);
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_LVT_THERMAL, LVT_MASKED);
+    write(REG_LVT_PMI, LVT_MASKED);
+    write(REG_LVT_LINT0, LVT_MASKED);
+    write(REG_LVT_LINT1, LVT_DELIVERY_NMI);
+    write(REG_LVT_ERROR, VECTOR_ERROR as u32);
+
+    // The ESR must be written before it is read, which also clears any stale errors.
+    write(REG_ESR, 0);
+    write(REG_ESR, 0);
+
+    write(REG_SVR, SVR_ENABLE | VECTOR_SPURIOUS as u32);
+    eoi();
+
+    log::debug!(
+        "lapic: id {}, version {:#x}, {}",
+        id(),
+        read(REG_VERSION) & 0xff,
+        if X2APIC.load(Ordering::Relaxed) {
+            "x2apic"
+        } else {
+            "xapic"
+        }
+    );
+}
+
+fn error_interrupt(_frame: &mut TrapFrame) {
+    let esr = unsafe {
+        write(REG_ESR, 0);
+        read(REG_ESR)
+    };
+    log::error!("lapic: error interrupt, esr = {esr:#x}");
+}
+
+/// Returns the APIC ID of the current CPU
+pub fn id() -> u32 {
+    let id = unsafe { read(REG_ID) };
+    if X2APIC.load(Ordering::Relaxed) {
+        id
+    } else {
+        id >> 24
+    }
+}
+
+/// Signal the end of the interrupt currently being serviced
+#[inline]
+pub fn eoi() {
+    unsafe { write(REG_EOI, 0) };
+}
+
+/// Deliver performance monitoring interrupts to this CPU as NMIs
+///
+/// The processor masks the LVT entry each time a PMI is delivered, so this must be called
+/// again to re-arm it.
+pub fn unmask_pmi() {
+    unsafe { write(REG_LVT_PMI, LVT_DELIVERY_NMI) };
+}
+
+pub fn mask_pmi() {
+    unsafe { write(REG_LVT_PMI, LVT_MASKED) };
+}
+
+/*
+ * Timer
+ */
+
+/// Measure the frequency of the timer
+///
+/// This only needs to be done once, on the BSP. All Local APIC timers in the system are
+/// driven by the same clock.
+pub fn calibrate_timer() {
+    const CALIBRATION_US: u64 = 10_000;
+
+    let token = trap::disable();
+    let elapsed = unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        write(REG_LVT_TIMER, LVT_MASKED | LVT_TIMER_ONESHOT);
+        write(REG_TIMER_INIT, u32::MAX);
+        pit::delay_us(CALIBRATION_US);
+        let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+        write(REG_TIMER_INIT, 0);
+        elapsed
+    };
+    trap::enable(token);
+
+    let frequency = elapsed as u64 * (1_000_000 / CALIBRATION_US);
+    TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+    log::info!("lapic: timer frequency is {} kHz", frequency / 1000);
+}
+
+fn ns_to_ticks(ns: u64) -> u32 {
+    let frequency = TIMER_FREQUENCY.load(Ordering::Relaxed);
+    debug_assert!(frequency != 0, "lapic timer used before calibration");
+    let ticks = ns as u128 * frequency as u128 / 1_000_000_000;
+    ticks.clamp(1, u32::MAX as u128) as u32
+}
+
+fn ns_to_tsc(ns: u64) -> u64 {
+    (ns as u128 * tsc::frequency() as u128 / 1_000_000_000) as u64
+}
+
+fn tsc_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / tsc::frequency() as u128) as u64
+}
+
+/// Called from interrupt context when the timer fires
+pub type TimerHandler = fn();
+
+/// [`TimerHandler`] of the timer's user, or zero
+static TIMER_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+const MAX_TIMER_HOOKS: usize = 4;
+
+/// Housekeeping work run periodically, see [`add_timer_hook()`]
+#[derive(Clone, Copy)]
+struct TimerHook {
+    func:     fn(),
+    /// Interval in TSC ticks
+    interval: u64,
+    /// TSC value at which the hook is next due
+    due:      u64,
+}
+
+/// Per-CPU state of the timer
+///
+/// The timer is shared between a single user, which programs it with [`timer_oneshot()`],
+/// [`timer_periodic()`] or [`timer_deadline()`], and housekeeping hooks. The hardware is
+/// always armed for whichever is due first.
+pub(super) struct TimerCpu {
+    /// TSC value at which the user's event fires, or `u64::MAX`
+    deadline: u64,
+    /// Period of the user's event in TSC ticks, or zero for a single event
+    period:   u64,
+    hooks:    [Option<TimerHook>; MAX_TIMER_HOOKS],
+}
+
+impl TimerCpu {
+    pub(super) const fn new() -> TimerCpu {
+        Self {
+            deadline: u64::MAX,
+            period:   0,
+            hooks:    [None; MAX_TIMER_HOOKS],
+        }
+    }
+
+    /// Program the hardware for the next event
+    unsafe fn arm(&self) {
+        let next = self
+            .hooks
+            .iter()
+            .flatten()
+            .map(|hook| hook.due)
+            .fold(self.deadline, u64::min);
+
+        if next == u64::MAX {
+            write(REG_LVT_TIMER, LVT_MASKED);
+            write(REG_TIMER_INIT, 0);
+        } else if has_tsc_deadline() {
+            write(REG_LVT_TIMER, LVT_TIMER_TSC_DEADLINE | VECTOR_TIMER as u32);
+            // The write to the LVT must be ordered before the MSR write when using the xAPIC
+            // MMIO interface, `wrmsr` is not serializing for this MSR.
+            asm!("mfence", options(nostack, preserves_flags));
+            msr::wrmsr(msr::IA32_TSC_DEADLINE, next);
+        } else {
+            let ns = tsc_to_ns(next.saturating_sub(tsc::read()));
+            write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+            write(REG_LVT_TIMER, LVT_TIMER_ONESHOT | VECTOR_TIMER as u32);
+            write(REG_TIMER_INIT, ns_to_ticks(ns));
+        }
+    }
+}
+
+/// Run `f` on the timer state of the current CPU, then reprogram the hardware
+fn with_timer<R>(f: impl FnOnce(&mut TimerCpu) -> R) -> R {
+    let token = trap::disable();
+    let result = unsafe {
+        let timer = &mut *addr_of_mut!((*cpu::this_cpu()).md_data.timer);
+        let result = f(timer);
+        timer.arm();
+        result
+    };
+    trap::enable(token);
+    result
+}
+
+fn timer_interrupt(_: &mut TrapFrame) {
+    let now = tsc::read();
+    let mut hooks = [None; MAX_TIMER_HOOKS];
+
+    let fire = with_timer(|timer| {
+        for (hook, due) in timer.hooks.iter_mut().flatten().zip(&mut hooks) {
+            if hook.due <= now {
+                hook.due = now + hook.interval;
+                *due = Some(hook.func);
+            }
+        }
+
+        let fire = timer.deadline <= now;
+        if fire {
+            timer.deadline = match timer.period {
+                0 => u64::MAX,
+                // Don't try to catch up on periods which were missed.
+                period => cmp::max(timer.deadline + period, now + 1),
+            };
+        }
+        fire
+    });
+
+    // Called without the timer state borrowed, they may reprogram the timer.
+    for func in hooks.into_iter().flatten() {
+        func();
+    }
+    if fire {
+        match TIMER_HANDLER.load(Ordering::Acquire) {
+            0 => {}
+            handler => unsafe { core::mem::transmute::<usize, TimerHandler>(handler)() },
+        }
+    }
+}
+
+/// Set the function called when the timer fires
+///
+/// This is shared by every CPU.
+pub fn set_timer_handler(handler: TimerHandler) {
+    TIMER_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+/// Run `func` on the current CPU every `interval_ns` nanoseconds, from interrupt context
+///
+/// Hooks run independently of the events programmed by the timer's user.
+///
+/// # Panics
+///
+/// This function will panic if the current CPU has no free hook slots.
+pub fn add_timer_hook(interval_ns: u64, func: fn()) {
+    let interval = ns_to_tsc(interval_ns);
+    with_timer(|timer| {
+        let slot = timer.hooks.iter_mut().find(|hook| hook.is_none());
+        *slot.expect("too many timer hooks") = Some(TimerHook {
+            func,
+            interval,
+            due: tsc::read() + interval,
+        });
+    });
+}
+
+/// Raise a single timer interrupt after `ns` nanoseconds
+pub fn timer_oneshot(ns: u64) {
+    let deadline = tsc::read() + ns_to_tsc(ns);
+    with_timer(|timer| {
+        timer.deadline = deadline;
+        timer.period = 0;
+    });
+}
+
+/// Raise a timer interrupt every `ns` nanoseconds
+pub fn timer_periodic(ns: u64) {
+    let period = ns_to_tsc(ns).max(1);
+    let deadline = tsc::read() + period;
+    with_timer(|timer| {
+        timer.deadline = deadline;
+        timer.period = period;
+    });
+}
+
+/// Returns `true` if the timer supports TSC-deadline mode
+pub fn has_tsc_deadline() -> bool {
+    CPU_FEATURES[CpuFeat::TSC_DEADLINE]
+}
+
+/// Raise a timer interrupt once the TSC reaches `deadline`
+///
+/// The timer is used in TSC-deadline mode when supported, and emulated in one-shot mode
+/// otherwise.
+pub fn timer_deadline(deadline: u64) {
+    with_timer(|timer| {
+        timer.deadline = deadline;
+        timer.period = 0;
+    });
+}
+
+/// Stop the timer
+///
+/// Housekeeping hooks keep running.
+pub fn timer_stop() {
+    with_timer(|timer| {
+        timer.deadline = u64::MAX;
+        timer.period = 0;
+    });
+}
+
+/*
+ * Inter-Processor Interrupts
+ */
+
+/// Destination of an inter-processor interrupt
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpiTarget {
+    /// The CPU with the given APIC ID
+    Apic(u32),
+    /// The current CPU
+    This,
+    /// All CPUs, including the current one
+    All,
+    /// All CPUs, except the current one
+    Others,
+}
+
+/// Send a fixed interrupt on `vector`
+pub fn send_ipi(target: IpiTarget, vector: u8) {
+    send(target, ICR_DELIVERY_FIXED | vector as u32);
+}
+
+/// Send a non-maskable interrupt
+pub fn send_nmi(target: IpiTarget) {
+    send(target, ICR_DELIVERY_NMI);
+}
+
+/// Send an INIT IPI, resetting the target processor(s)
+pub fn send_init(target: IpiTarget) {
+    send(target, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Send a Startup IPI
+///
+/// The target begins executing in real mode at physical address `page << 12`.
+pub fn send_sipi(target: IpiTarget, page: u8) {
+    send(target, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+fn send(target: IpiTarget, command: u32) {
+    let (shorthand, dest) = match target {
+        IpiTarget::Apic(id) => (0, id),
+        IpiTarget::This => (ICR_SHORTHAND_SELF, 0),
+        IpiTarget::All => (ICR_SHORTHAND_ALL, 0),
+        IpiTarget::Others => (ICR_SHORTHAND_OTHERS, 0),
+    };
+    let command = command | shorthand;
+
+    // In xAPIC mode the ICR is written in two halves, which must not be interleaved with
+    // another IPI sent from an interrupt handler.
+    let token = trap::disable();
+    unsafe {
+        if X2APIC.load(Ordering::Relaxed) {
+            msr::wrmsr(msr::IA32_X2APIC_ICR, (dest as u64) << 32 | command as u64);
+        } else {
+            write(REG_ICR_HI, dest << 24);
+            write(REG_ICR_LO, command);
+            while read(REG_ICR_LO) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+    trap::enable(token);
+}
@@ -0,0 +1,237 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Last Branch Records
+//!
+//! When enabled with the `debug_lbr` feature, the processor records the source and
+//! destination of recently taken branches. The records are frozen as soon as the kernel
+//! panics or takes a fatal exception and printed along with the crash report.
+//!
+//! Architectural LBRs are used if available. Otherwise the model-specific LBR stack of
+//! older Intel processors is used, which also provides the Last Exception Record: the last
+//! branch taken before an exception or interrupt was delivered.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::msr;
+use crate::sync::lazy::Lazy;
+
+const DEBUGCTL_LBR: u64 = 1 << 0;
+
+const LBR_CTL_ENABLE: u64 = 1 << 0;
+const LBR_CTL_OS: u64 = 1 << 1;
+const LBR_CTL_USR: u64 = 1 << 2;
+/// Record all branch types
+const LBR_CTL_BRANCH_TYPES: u64 = 0x7f << 16;
+
+// Model-specific LBR stack
+const MSR_LASTBRANCH_TOS: u32 = 0x1c9;
+const MSR_LASTBRANCH_0_FROM_IP: u32 = 0x680;
+const MSR_LASTBRANCH_0_TO_IP: u32 = 0x6c0;
+
+/// Strip the flags which some LBR formats store in the upper bits of an address
+fn sign_extend_48(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Architectural,
+    ModelSpecific,
+}
+
+struct LbrInfo {
+    kind:  Kind,
+    depth: u32,
+}
+
+static LBR_INFO: Lazy<Option<LbrInfo>> = Lazy::new(LbrInfo::detect);
+
+/// Set once the boot CPU has started recording
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+impl LbrInfo {
+    fn detect() -> Option<LbrInfo> {
+        let max_leaf = unsafe { __cpuid(0).eax };
+
+        // Architectural LBRs: CPUID.(EAX=7,ECX=0):EDX[19], depths enumerated in leaf 0x1c.
+        if max_leaf >= 0x1c && unsafe { __cpuid_count(7, 0).edx } & (1 << 19) != 0 {
+            let depths = unsafe { __cpuid_count(0x1c, 0).eax } & 0xff;
+            if depths != 0 {
+                let depth = 8 * (32 - depths.leading_zeros());
+                return Some(LbrInfo {
+                    kind: Kind::Architectural,
+                    depth,
+                });
+            }
+        }
+
+        let vendor = unsafe { __cpuid(0) };
+        if [vendor.ebx, vendor.edx, vendor.ecx] != [0x756e6547, 0x49656e69, 0x6c65746e] {
+            return None;
+        }
+
+        let leaf1 = unsafe { __cpuid(1) };
+        let family = (leaf1.eax >> 8) & 0xf;
+        let model = (leaf1.eax >> 4) & 0xf | (leaf1.eax >> 12) & 0xf0;
+        if family != 6 {
+            return None;
+        }
+
+        // The depth of the model-specific stack isn't enumerated.
+        let depth = match model {
+            // Skylake and later client and server cores
+            0x4e | 0x55 | 0x5e | 0x66 | 0x6a | 0x6c | 0x7d | 0x7e | 0x8c | 0x8d | 0x8e | 0x9e
+            | 0xa5 | 0xa6 | 0xa7 => 32,
+            // Nehalem through Broadwell
+            0x1a | 0x1e | 0x1f | 0x25 | 0x2a | 0x2c | 0x2d | 0x2e | 0x2f | 0x3a | 0x3c | 0x3d
+            | 0x3e | 0x3f | 0x45 | 0x46 | 0x47 | 0x4f | 0x56 => 16,
+            _ => return None,
+        };
+
+        Some(LbrInfo {
+            kind: Kind::ModelSpecific,
+            depth,
+        })
+    }
+}
+
+/// Start recording branches on this CPU
+#[cfg_attr(not(feature = "debug_lbr"), allow(dead_code))]
+pub(super) unsafe fn init() {
+    let Some(info) = &*LBR_INFO else {
+        log::warn!("lbr: not supported on this processor");
+        return;
+    };
+
+    if info.kind == Kind::Architectural {
+        msr::wrmsr(msr::IA32_LBR_DEPTH, info.depth as u64);
+    }
+
+    if !ENABLED.swap(true, Ordering::Relaxed) {
+        log::info!("lbr: recording {} branches ({:?})", info.depth, info.kind);
+    }
+    resume();
+}
+
+/// Stop recording, preserving the current records
+///
+/// This is done as early as possible on a crash so that the records describe how we got
+/// there, rather than the path through the crash handler.
+#[inline]
+pub(super) fn freeze() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    unsafe {
+        match LBR_INFO.as_ref().map(|info| info.kind) {
+            Some(Kind::Architectural) => msr::wrmsr(msr::IA32_LBR_CTL, 0),
+            Some(Kind::ModelSpecific) => msr::wrmsr(
+                msr::IA32_DEBUGCTL,
+                msr::rdmsr(msr::IA32_DEBUGCTL) & !DEBUGCTL_LBR,
+            ),
+            None => {}
+        }
+    }
+}
+
+/// Resume recording after [`freeze()`]
+pub(super) fn resume() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    unsafe {
+        match LBR_INFO.as_ref().map(|info| info.kind) {
+            Some(Kind::Architectural) => msr::wrmsr(
+                msr::IA32_LBR_CTL,
+                LBR_CTL_ENABLE | LBR_CTL_OS | LBR_CTL_USR | LBR_CTL_BRANCH_TYPES,
+            ),
+            Some(Kind::ModelSpecific) => msr::wrmsr(
+                msr::IA32_DEBUGCTL,
+                msr::rdmsr(msr::IA32_DEBUGCTL) | DEBUGCTL_LBR,
+            ),
+            None => {}
+        }
+    }
+}
+
+/// Print the recorded branches, most recent first
+#[cfg_attr(not(feature = "debug_lbr"), allow(dead_code))]
+pub(super) fn dump() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(info) = &*LBR_INFO else {
+        return;
+    };
+
+    log::error!("last branch records (most recent first):");
+
+    for i in 0..info.depth {
+        let (from, to) = unsafe {
+            match info.kind {
+                // The architectural stack always keeps the most recent record in entry 0.
+                Kind::Architectural => (
+                    msr::rdmsr(msr::IA32_LBR_x_FROM_IP + i),
+                    msr::rdmsr(msr::IA32_LBR_x_TO_IP + i),
+                ),
+                // The model-specific stack is a ring, starting at the top-of-stack index.
+                Kind::ModelSpecific => {
+                    let tos = msr::rdmsr(MSR_LASTBRANCH_TOS) as u32;
+                    let index = (tos + info.depth - i) % info.depth;
+                    (
+                        sign_extend_48(msr::rdmsr(MSR_LASTBRANCH_0_FROM_IP + index)),
+                        msr::rdmsr(MSR_LASTBRANCH_0_TO_IP + index),
+                    )
+                }
+            }
+        };
+
+        if from == 0 && to == 0 {
+            continue;
+        }
+        log::error!("  {i:2}: {from:#018x} -> {to:#018x}");
+    }
+
+    if info.kind == Kind::ModelSpecific {
+        let (from, to) = unsafe {
+            (
+                msr::rdmsr(msr::IA32_LER_FROM_IP),
+                msr::rdmsr(msr::IA32_LER_TO_IP),
+            )
+        };
+        log::error!("last exception record: {from:#018x} -> {to:#018x}");
+    }
+}
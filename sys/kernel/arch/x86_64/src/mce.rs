@@ -0,0 +1,303 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Machine Check Architecture
+//!
+//! Hardware errors are logged in a set of per-CPU error-reporting banks. Uncorrected errors
+//! raise #MC, which is delivered on its own IST stack. Corrected errors are only recorded
+//! in the banks, so they are polled for periodically from the Local APIC timer.
+
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::control::Cr4;
+
+use super::{lapic, msr, trap::TrapFrame};
+use crate::cpu;
+
+const MCG_CAP_COUNT: u64 = 0xff;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+/// Software error recovery is supported, enabling the `S` and `AR` status bits
+const MCG_CAP_SER_P: u64 = 1 << 24;
+
+/// Restart IP valid, execution can resume at the interrupted instruction
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// Error IP valid, the interrupted instruction is related to the error
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+const MCI_STATUS_S: u64 = 1 << 56;
+const MCI_STATUS_AR: u64 = 1 << 55;
+const MCI_STATUS_CORRECTED_COUNT_SHIFT: u64 = 38;
+const MCI_STATUS_CORRECTED_COUNT_MASK: u64 = 0x7fff;
+
+/// Interval at which the banks are checked for corrected errors
+const POLL_INTERVAL_NS: u64 = 5_000_000_000;
+
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+const fn mci_ctl(bank: u32) -> u32 {
+    msr::IA32_MC0_CTL + 4 * bank
+}
+
+const fn mci_status(bank: u32) -> u32 {
+    msr::IA32_MC0_STATUS + 4 * bank
+}
+
+const fn mci_addr(bank: u32) -> u32 {
+    msr::IA32_MC0_ADDR + 4 * bank
+}
+
+const fn mci_misc(bank: u32) -> u32 {
+    msr::IA32_MC0_MISC + 4 * bank
+}
+
+fn num_banks() -> u32 {
+    (unsafe { msr::rdmsr(msr::IA32_MCG_CAP) } & MCG_CAP_COUNT) as u32
+}
+
+/// Enable machine check reporting on this CPU
+///
+/// Errors left in the banks from before the last reset are logged and cleared.
+pub(super) unsafe fn init() {
+    let leaf1 = __cpuid(1);
+    let has_mce = leaf1.edx & (1 << 7) != 0;
+    let has_mca = leaf1.edx & (1 << 14) != 0;
+    if !has_mce || !has_mca {
+        log::warn!("mce: machine check architecture is not supported");
+        return;
+    }
+
+    let cap = msr::rdmsr(msr::IA32_MCG_CAP);
+    if cap & MCG_CAP_CTL_P != 0 {
+        msr::wrmsr(msr::IA32_MCG_CTL, !0);
+    }
+
+    // Bank 0 is owned by the firmware on older Intel P6-family processors.
+    let vendor = __cpuid(0);
+    let is_intel = [vendor.ebx, vendor.edx, vendor.ecx] == [0x756e6547, 0x49656e69, 0x6c65746e];
+    let family = (leaf1.eax >> 8) & 0xf;
+    let model = (leaf1.eax >> 4) & 0xf | (leaf1.eax >> 12) & 0xf0;
+    let first_bank = match is_intel && family == 6 && model < 0x1a {
+        true => 1,
+        false => 0,
+    };
+
+    for bank in first_bank..num_banks() {
+        msr::wrmsr(mci_ctl(bank), !0);
+    }
+
+    poll();
+
+    let mut cr4 = Cr4::read();
+    cr4 |= Cr4::MCE;
+    cr4.write();
+
+    AVAILABLE.store(true, Ordering::Relaxed);
+    log::debug!("mce: {} banks, mcg_cap={cap:#x}", num_banks());
+}
+
+/// Start polling for corrected errors on this CPU
+///
+/// The poll runs as a Local APIC timer hook, which requires the TSC to be calibrated.
+pub(super) fn start_polling() {
+    if !AVAILABLE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    lapic::add_timer_hook(POLL_INTERVAL_NS, || unsafe { poll() });
+}
+
+/// The contents of an error-reporting bank
+struct BankError {
+    bank:   u32,
+    status: u64,
+    addr:   u64,
+    misc:   u64,
+}
+
+impl BankError {
+    unsafe fn read(bank: u32) -> Option<BankError> {
+        let status = msr::rdmsr(mci_status(bank));
+        if status & MCI_STATUS_VAL == 0 {
+            return None;
+        }
+
+        let addr = match status & MCI_STATUS_ADDRV {
+            0 => 0,
+            _ => msr::rdmsr(mci_addr(bank)),
+        };
+        let misc = match status & MCI_STATUS_MISCV {
+            0 => 0,
+            _ => msr::rdmsr(mci_misc(bank)),
+        };
+
+        Some(BankError {
+            bank,
+            status,
+            addr,
+            misc,
+        })
+    }
+
+    unsafe fn clear(&self) {
+        msr::wrmsr(mci_status(self.bank), 0);
+    }
+
+    fn is_uncorrected(&self) -> bool {
+        self.status & MCI_STATUS_UC != 0
+    }
+
+    /// Returns `true` if execution cannot safely continue after this error
+    fn is_fatal(&self, ser_p: bool) -> bool {
+        if !self.is_uncorrected() {
+            return false;
+        }
+        // Without software error recovery all uncorrected errors are fatal. With it, only
+        // uncorrected errors not requiring action (UCNA) can be ignored.
+        self.status & MCI_STATUS_PCC != 0 || !ser_p || self.status & MCI_STATUS_AR != 0
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.status as u16;
+        write!(
+            f,
+            "bank {}: {} {} (code {code:#06x}, model-specific {:#06x})",
+            self.bank,
+            if self.is_uncorrected() { "uncorrected" } else { "corrected" },
+            describe_error_code(code),
+            (self.status >> 16) as u16,
+        )?;
+
+        for (bit, name) in [
+            (MCI_STATUS_OVER, "overflow"),
+            (MCI_STATUS_EN, "signaled"),
+            (MCI_STATUS_PCC, "context corrupt"),
+            (MCI_STATUS_S, "signaling"),
+            (MCI_STATUS_AR, "action required"),
+        ] {
+            if self.status & bit != 0 {
+                write!(f, ", {name}")?;
+            }
+        }
+
+        if !self.is_uncorrected() {
+            let count =
+                self.status >> MCI_STATUS_CORRECTED_COUNT_SHIFT & MCI_STATUS_CORRECTED_COUNT_MASK;
+            write!(f, ", count {count}")?;
+        }
+
+        write!(f, "\n    status {:#018x}", self.status)?;
+        if self.status & MCI_STATUS_ADDRV != 0 {
+            write!(f, " addr {:#018x}", self.addr)?;
+        }
+        if self.status & MCI_STATUS_MISCV != 0 {
+            write!(f, " misc {:#018x}", self.misc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the class of an architectural MCA error code
+fn describe_error_code(code: u16) -> &'static str {
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified error",
+        0x0002 => "microcode ROM parity error",
+        0x0003 => "external error",
+        0x0004 => "FRC error",
+        0x0005 => "internal parity error",
+        0x0006 => "SMM handler code access violation",
+        0x0400 => "internal timer error",
+        0x0e0b => "I/O error",
+        _ if code & 0xeffc == 0x000c => "generic cache hierarchy error",
+        _ if code & 0xeff0 == 0x0010 => "TLB error",
+        _ if code & 0xef80 == 0x0080 => "memory controller error",
+        _ if code & 0xef00 == 0x0100 => "cache hierarchy error",
+        _ if code & 0xe800 == 0x0800 => "bus/interconnect error",
+        _ if code & 0xfc00 == 0x0400 => "internal unclassified error",
+        _ => "unknown error",
+    }
+}
+
+fn this_cpu_id() -> usize {
+    unsafe { (*cpu::this_cpu()).cpu_id }
+}
+
+/// Log and clear any errors recorded in this CPU's banks
+unsafe fn poll() {
+    for bank in 0..num_banks() {
+        if let Some(error) = BankError::read(bank) {
+            log::warn!("mce: cpu{} {error}", this_cpu_id());
+            error.clear();
+        }
+    }
+}
+
+/// Handle a machine check exception
+///
+/// Returns `true` if the interrupted context may be resumed.
+pub(super) unsafe fn machine_check(frame: &TrapFrame) -> bool {
+    let mcg_status = msr::rdmsr(msr::IA32_MCG_STATUS);
+    let ser_p = msr::rdmsr(msr::IA32_MCG_CAP) & MCG_CAP_SER_P != 0;
+
+    log::error!(
+        "mce: cpu{} machine check at rip {:#018x}, mcg_status {mcg_status:#x}{}{}",
+        this_cpu_id(),
+        frame.rip,
+        if mcg_status & MCG_STATUS_RIPV != 0 { ", restartable" } else { "" },
+        if mcg_status & MCG_STATUS_EIPV != 0 { ", at rip" } else { "" },
+    );
+
+    let mut fatal = mcg_status & MCG_STATUS_RIPV == 0;
+    for bank in 0..num_banks() {
+        if let Some(error) = BankError::read(bank) {
+            log::error!("mce: {error}");
+            fatal |= error.is_fatal(ser_p);
+            error.clear();
+        }
+    }
+
+    // Clearing MCIP re-arms machine checks, a second one before this would shut down.
+    msr::wrmsr(msr::IA32_MCG_STATUS, 0);
+
+    !fatal
+}
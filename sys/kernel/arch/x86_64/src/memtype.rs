@@ -0,0 +1,369 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Memory Types
+//!
+//! The memory type of an access is a combination of the MTRRs, which are set up by firmware
+//! and describe physical memory, and the PAT entry selected by the page tables. We program
+//! the PAT with a fixed layout and leave the MTRRs alone.
+
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use cpu_features::CpuFeat;
+
+use super::{
+    cpu::CPU_FEATURES,
+    hat::{PageSize, KERNEL_HAT},
+    msr,
+};
+use crate::{
+    sync::lazy::Lazy,
+    vm::{PhysAddr, Prot, VirtAddr, PAGE_SIZE},
+};
+
+/// Memory type encoding shared by the PAT and MTRRs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    /// Uncacheable, but may be overridden by a write-combining MTRR (PAT only)
+    UncacheableMinus = 7,
+}
+
+impl MemoryType {
+    fn from_bits(bits: u64) -> Option<MemoryType> {
+        match bits {
+            0 => Some(MemoryType::Uncacheable),
+            1 => Some(MemoryType::WriteCombining),
+            4 => Some(MemoryType::WriteThrough),
+            5 => Some(MemoryType::WriteProtected),
+            6 => Some(MemoryType::WriteBack),
+            7 => Some(MemoryType::UncacheableMinus),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MemoryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MemoryType::Uncacheable => "UC",
+            MemoryType::WriteCombining => "WC",
+            MemoryType::WriteThrough => "WT",
+            MemoryType::WriteProtected => "WP",
+            MemoryType::WriteBack => "WB",
+            MemoryType::UncacheableMinus => "UC-",
+        })
+    }
+}
+
+/// Caching mode of a mapping
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CacheMode {
+    /// Normal memory
+    #[default]
+    WriteBack,
+    /// Framebuffers and other memory written in bulk but rarely read
+    WriteCombining,
+    WriteThrough,
+    /// Device MMIO
+    Uncached,
+}
+
+/// The PAT layout
+///
+/// The first four entries match the power-on default, so mappings which only use PWT and PCD
+/// mean the same thing with and without the PAT. The upper half adds write-combining.
+const PAT_LAYOUT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncacheableMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteCombining,
+    MemoryType::WriteThrough,
+    MemoryType::UncacheableMinus,
+    MemoryType::Uncacheable,
+];
+
+impl CacheMode {
+    /// Returns the index of the PAT entry for this mode
+    ///
+    /// Bit 0 selects PWT, bit 1 selects PCD and bit 2 selects the PAT bit of a page table entry.
+    pub(super) fn pat_index(self) -> usize {
+        match self {
+            CacheMode::WriteBack => 0,
+            CacheMode::WriteThrough => 1,
+            CacheMode::Uncached => 3,
+            // Fall back to uncached if the PAT is not available, which is still correct,
+            // just slower.
+            CacheMode::WriteCombining if CPU_FEATURES[CpuFeat::PAT] => 4,
+            CacheMode::WriteCombining => 3,
+        }
+    }
+}
+
+/// Region of the kernel address space reserved for device mappings
+///
+/// Like kernel stacks, addresses are handed out linearly and never reused.
+const DEVICE_REGION: Range<usize> = 0xfffffe8000000000..0xffffff0000000000;
+
+static NEXT_DEVICE: AtomicUsize = AtomicUsize::new(DEVICE_REGION.start);
+
+/// Map `size` bytes of device memory at `phys` into the kernel address space
+///
+/// The direct map uses write-back, which is not appropriate for MMIO registers. `phys` does
+/// not need to be page aligned, the returned address points at `phys` itself.
+///
+/// # Panics
+///
+/// This function will panic if the device region has been exhausted.
+pub fn map_device(phys: PhysAddr, size: usize, cache: CacheMode) -> VirtAddr {
+    let offset = phys.0 & (PAGE_SIZE - 1);
+    let len = (offset + size).next_multiple_of(PAGE_SIZE);
+
+    let base = NEXT_DEVICE.fetch_add(len, Ordering::Relaxed);
+    assert!(base + len <= DEVICE_REGION.end, "kernel device region exhausted");
+
+    KERNEL_HAT
+        .lock()
+        .map_pages(
+            VirtAddr(base),
+            PhysAddr(phys.0 - offset),
+            len,
+            PageSize::Size4KiB,
+            Prot::READ | Prot::WRITE,
+            cache,
+        )
+        .unwrap();
+
+    VirtAddr(base + offset)
+}
+
+/// Program the PAT on this CPU
+///
+/// Only the upper half of the PAT changes from the default, and nothing is mapped with those
+/// entries before this runs on each CPU, so there are no stale TLB entries to flush.
+pub(super) unsafe fn init() {
+    if !CPU_FEATURES[CpuFeat::PAT] {
+        return;
+    }
+
+    let pat = PAT_LAYOUT
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, &ty)| pat | (ty as u64) << (i * 8));
+
+    asm!("wbinvd", options(nostack, preserves_flags));
+    msr::wrmsr(msr::IA32_PAT, pat);
+}
+
+const MTRRCAP_VCNT: u64 = 0xff;
+const MTRRCAP_FIX: u64 = 1 << 8;
+const MTRRCAP_WC: u64 = 1 << 10;
+
+const MTRR_DEF_TYPE_FE: u64 = 1 << 10;
+const MTRR_DEF_TYPE_E: u64 = 1 << 11;
+
+const MTRR_PHYSMASK_VALID: u64 = 1 << 11;
+
+/// Maximum number of variable-range MTRRs we keep track of
+const MAX_VARIABLE_MTRRS: usize = 16;
+
+/// Fixed-range MTRR registers, with the base and size of the range covered by each of their
+/// eight fields
+const FIXED_MTRRS: [(u32, usize, usize); 11] = [
+    (msr::IA32_MTRR_FIX64K_00000, 0x00000, 0x10000),
+    (msr::IA32_MTRR_FIX64K_80000, 0x80000, 0x4000),
+    (msr::IA32_MTRR_FIX64K_A0000, 0xa0000, 0x4000),
+    (msr::IA32_MTRR_FIX4K_C0000, 0xc0000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_C8000, 0xc8000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_D0000, 0xd0000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_D8000, 0xd8000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_E0000, 0xe0000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_E8000, 0xe8000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_F0000, 0xf0000, 0x1000),
+    (msr::IA32_MTRR_FIX4K_F8000, 0xf8000, 0x1000),
+];
+
+#[derive(Clone, Copy, Debug)]
+struct VariableMtrr {
+    base: u64,
+    mask: u64,
+    ty:   MemoryType,
+}
+
+impl VariableMtrr {
+    fn contains(&self, addr: u64) -> bool {
+        addr & self.mask == self.base & self.mask
+    }
+}
+
+/// The MTRR configuration left by firmware
+struct Mtrrs {
+    enabled:         bool,
+    fixed_enabled:   bool,
+    write_combining: bool,
+    default:         MemoryType,
+    fixed:           [u64; 11],
+    variable:        [Option<VariableMtrr>; MAX_VARIABLE_MTRRS],
+}
+
+static MTRRS: Lazy<Mtrrs> = Lazy::new(|| unsafe { Mtrrs::read() });
+
+impl Mtrrs {
+    unsafe fn read() -> Mtrrs {
+        let mut mtrrs = Mtrrs {
+            enabled:         false,
+            fixed_enabled:   false,
+            write_combining: false,
+            default:         MemoryType::Uncacheable,
+            fixed:           [0; 11],
+            variable:        [None; MAX_VARIABLE_MTRRS],
+        };
+
+        if !CPU_FEATURES[CpuFeat::MTRR] {
+            return mtrrs;
+        }
+
+        let cap = msr::rdmsr(msr::IA32_MTRRCAP);
+        let def_type = msr::rdmsr(msr::IA32_MTRR_DEF_TYPE);
+
+        mtrrs.enabled = def_type & MTRR_DEF_TYPE_E != 0;
+        mtrrs.fixed_enabled = cap & MTRRCAP_FIX != 0 && def_type & MTRR_DEF_TYPE_FE != 0;
+        mtrrs.write_combining = cap & MTRRCAP_WC != 0;
+        mtrrs.default = MemoryType::from_bits(def_type & 0xff).unwrap_or(MemoryType::Uncacheable);
+
+        if mtrrs.fixed_enabled {
+            for (fixed, &(msr, _, _)) in mtrrs.fixed.iter_mut().zip(FIXED_MTRRS.iter()) {
+                *fixed = msr::rdmsr(msr);
+            }
+        }
+
+        let count = (cap & MTRRCAP_VCNT) as usize;
+        if count > MAX_VARIABLE_MTRRS {
+            log::warn!("mtrr: ignoring {} variable ranges", count - MAX_VARIABLE_MTRRS);
+        }
+
+        // Bits above the physical address width are reserved in the base and mask.
+        let phys_bits = match __cpuid(0x80000000).eax >= 0x80000008 {
+            true => __cpuid(0x80000008).eax & 0xff,
+            false => 36,
+        };
+        let addr_mask = ((1u64 << phys_bits) - 1) & !0xfff;
+
+        for i in 0..count.min(MAX_VARIABLE_MTRRS) {
+            let base = msr::rdmsr(msr::IA32_MTRR_PHYSBASE0 + 2 * i as u32);
+            let mask = msr::rdmsr(msr::IA32_MTRR_PHYSMASK0 + 2 * i as u32);
+            if mask & MTRR_PHYSMASK_VALID == 0 {
+                continue;
+            }
+            mtrrs.variable[i] = MemoryType::from_bits(base & 0xff).map(|ty| VariableMtrr {
+                base: base & addr_mask,
+                mask: mask & addr_mask,
+                ty,
+            });
+        }
+
+        mtrrs
+    }
+
+    fn memory_type(&self, addr: u64) -> MemoryType {
+        if !self.enabled {
+            return MemoryType::Uncacheable;
+        }
+
+        if self.fixed_enabled && addr < 0x100000 {
+            let index = FIXED_MTRRS
+                .iter()
+                .rposition(|&(_, base, _)| addr as usize >= base)
+                .unwrap();
+            let (_, base, size) = FIXED_MTRRS[index];
+            let field = (addr as usize - base) / size;
+            let bits = self.fixed[index] >> (field * 8) & 0xff;
+            return MemoryType::from_bits(bits).unwrap_or(MemoryType::Uncacheable);
+        }
+
+        // When ranges overlap, UC wins over everything and WT wins over WB. Other combinations
+        // are undefined, so we just take the first.
+        let mut result = None;
+        for mtrr in self.variable.iter().flatten().filter(|mtrr| mtrr.contains(addr)) {
+            result = match (result, mtrr.ty) {
+                (_, MemoryType::Uncacheable) => return MemoryType::Uncacheable,
+                (None, ty) | (Some(MemoryType::WriteBack), ty @ MemoryType::WriteThrough) => {
+                    Some(ty)
+                }
+                (result, _) => result,
+            };
+        }
+
+        result.unwrap_or(self.default)
+    }
+}
+
+/// Returns the memory type the MTRRs assign to `addr`
+pub fn mtrr_type(addr: PhysAddr) -> MemoryType {
+    MTRRS.memory_type(addr.0 as u64)
+}
+
+/// Log the MTRR configuration
+pub(super) fn report_mtrrs() {
+    let mtrrs = &*MTRRS;
+
+    if !mtrrs.enabled {
+        log::info!("mtrr: disabled, all memory is uncacheable");
+        return;
+    }
+
+    log::info!(
+        "mtrr: default type {}, fixed ranges {}, write-combining {}",
+        mtrrs.default,
+        if mtrrs.fixed_enabled { "enabled" } else { "disabled" },
+        if mtrrs.write_combining { "supported" } else { "unsupported" },
+    );
+    for (i, mtrr) in mtrrs.variable.iter().enumerate() {
+        if let Some(mtrr) = mtrr {
+            log::info!(
+                "mtrr: {i}: base {:#014x} mask {:#014x} {}",
+                mtrr.base,
+                mtrr.mask,
+                mtrr.ty
+            );
+        }
+    }
+}
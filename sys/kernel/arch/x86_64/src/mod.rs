@@ -28,12 +28,32 @@
  * SPDX-License-Identifier: BSD-3-Clause
  */
 
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, ptr::addr_of_mut};
 use crate::cpu::Cpu;
 
 mod cpu;
-mod msr;
+mod extable;
+pub mod fpu;
+pub mod hat;
+pub mod ioapic;
+pub mod lapic;
+pub mod lbr;
+mod mce;
+pub mod memtype;
+pub mod msr;
+pub mod pio;
+mod pit;
+pub mod pmu;
+mod smp;
+pub mod spec;
+mod stack;
+mod syscall;
+mod tlb;
+pub mod topology;
 mod trap;
+pub mod tsc;
+
+pub use self::{cpu::set_kernel_stack, stack::KernelStack, trap::enter_user};
 
 pub fn hcf() -> ! {
     loop {
@@ -41,6 +61,13 @@ pub fn hcf() -> ! {
     }
 }
 
+/// Wait for interrupts, forever
+pub fn idle() -> ! {
+    loop {
+        unsafe { asm!("sti; hlt") };
+    }
+}
+
 unsafe fn port3f8_write(s: &str) {
     asm!(
         "rep outsb",
@@ -51,16 +78,42 @@ unsafe fn port3f8_write(s: &str) {
     );
 }
 
+static KERNEL_FILE_REQUEST: limine::KernelFileRequest = limine::KernelFileRequest::new();
+
 static mut CPU0_STORAGE: MaybeUninit<Cpu> = MaybeUninit::uninit();
 
 #[no_mangle]
 unsafe extern "C" fn _start() -> ! {
     port3f8_write("hello, world!\r\n");
 
+    if let Some(resp) = KERNEL_FILE_REQUEST.response() {
+        match core::str::from_utf8(resp.file().cmdline()) {
+            Ok(cmdline) => crate::cmdline::init(cmdline),
+            Err(_) => log::warn!("kernel command line is not valid utf-8, ignoring it"),
+        }
+    }
+
     let this_cpu = CPU0_STORAGE.as_mut_ptr();
+    addr_of_mut!((*this_cpu).cpu_id).write(0);
     cpu::early_init(this_cpu);
+    spec::report();
+    memtype::report_mtrrs();
+    lapic::init();
+    tlb::init();
+    fpu::init_flush_ipi();
+    lapic::calibrate_timer();
+    tsc::init();
+    mce::start_polling();
+    lapic::add_timer_hook(crate::time::UPDATE_INTERVAL_NS, crate::time::update);
 
     port3f8_write("hello, again!\r\n");
 
-    hcf();
+    cpu::init_stacks(this_cpu);
+    smp::init();
+
+    #[cfg(test)]
+    crate::test_main();
+
+    // Stay responsive to IPIs, the BSP is still registered as online.
+    idle();
 }
@@ -55,6 +55,227 @@ pub unsafe fn rdmsr(addr: u32) -> u64 {
     (value_hi as u64) << 32 | value_lo as u64
 }
 
+/// A `rdmsr` or `wrmsr` raised #GP, because the MSR does not exist or the value is invalid
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fault;
+
+/// Read an MSR, catching the #GP if it does not exist
+#[inline]
+pub fn rdmsr_safe(addr: u32) -> Result<u64, Fault> {
+    let value_lo: u32;
+    let value_hi: u32;
+    let fault: u32;
+
+    // If `rdmsr` faults, the trap handler resumes at label 3 with `fault` still set.
+    unsafe {
+        asm!(
+            "
+            2:
+                rdmsr
+                xor     {fault:e}, {fault:e}
+            3:
+                .pushsection .extable, \"a\"
+                .balign 4
+                .long   2b - .
+                .long   3b - .
+                .popsection
+            ",
+            fault = inout(reg) 1u32 => fault,
+            in("ecx") addr,
+            out("edx") value_hi,
+            out("eax") value_lo,
+            options(nomem, nostack)
+        );
+    }
+
+    match fault {
+        0 => Ok((value_hi as u64) << 32 | value_lo as u64),
+        _ => Err(Fault),
+    }
+}
+
+/// Write an MSR, catching the #GP if it does not exist or `value` is invalid
+///
+/// # Safety
+///
+/// The same as [`wrmsr()`], the write may still have side effects when it succeeds.
+#[inline]
+pub unsafe fn wrmsr_safe(addr: u32, value: u64) -> Result<(), Fault> {
+    let fault: u32;
+
+    asm!(
+        "
+        2:
+            wrmsr
+            xor     {fault:e}, {fault:e}
+        3:
+            .pushsection .extable, \"a\"
+            .balign 4
+            .long   2b - .
+            .long   3b - .
+            .popsection
+        ",
+        fault = inout(reg) 1u32 => fault,
+        in("ecx") addr,
+        in("edx") value >> 32,
+        in("eax") value,
+        options(nomem, nostack)
+    );
+
+    match fault {
+        0 => Ok(()),
+        _ => Err(Fault),
+    }
+}
+
+/// An MSR with a typed value
+pub trait Msr: Sized {
+    /// Address of the MSR
+    const ADDR: u32;
+
+    fn from_raw(value: u64) -> Self;
+
+    fn into_raw(self) -> u64;
+
+    /// Read the MSR
+    ///
+    /// # Safety
+    ///
+    /// The MSR must exist on this processor.
+    #[inline]
+    unsafe fn read() -> Self {
+        Self::from_raw(rdmsr(Self::ADDR))
+    }
+
+    /// Write the MSR
+    ///
+    /// # Safety
+    ///
+    /// The MSR must exist on this processor, and the write must not break any assumptions of
+    /// the kernel.
+    #[inline]
+    unsafe fn write(self) {
+        wrmsr(Self::ADDR, self.into_raw());
+    }
+
+    /// Read the MSR, catching the #GP if it does not exist
+    #[inline]
+    fn read_safe() -> Result<Self, Fault> {
+        rdmsr_safe(Self::ADDR).map(Self::from_raw)
+    }
+
+    /// Write the MSR, catching the #GP if it does not exist
+    ///
+    /// # Safety
+    ///
+    /// See [`Msr::write()`].
+    #[inline]
+    unsafe fn write_safe(self) -> Result<(), Fault> {
+        wrmsr_safe(Self::ADDR, self.into_raw())
+    }
+}
+
+macro_rules! typed_msrs {
+    ($(
+        $(#[$m:meta])*
+        struct $name:ident = $addr:path {
+            $($(#[$fm:meta])* const $flag:ident = $value:expr;)*
+        }
+    )*) => {
+        $(
+            bitflags::bitflags! {
+                $(#[$m])*
+                #[repr(transparent)]
+                #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+                pub struct $name : u64 {
+                    $($(#[$fm])* const $flag = $value;)*
+
+                    // Keep reserved and multi-bit fields intact through read-modify-write.
+                    const _ = !0;
+                }
+            }
+
+            impl Msr for $name {
+                const ADDR: u32 = $addr;
+
+                #[inline]
+                fn from_raw(value: u64) -> Self {
+                    Self::from_bits_retain(value)
+                }
+
+                #[inline]
+                fn into_raw(self) -> u64 {
+                    self.bits()
+                }
+            }
+        )*
+    };
+}
+
+typed_msrs! {
+    /// `IA32_EFER`
+    struct Efer = IA32_EFER {
+        /// `syscall`/`sysret` enable
+        const SCE = 1 << 0;
+        /// Long mode enable
+        const LME = 1 << 8;
+        /// Long mode active
+        const LMA = 1 << 10;
+        /// No-execute enable
+        const NXE = 1 << 11;
+    }
+
+    /// `IA32_APIC_BASE`
+    struct ApicBase = IA32_APIC_BASE {
+        /// This processor is the bootstrap processor
+        const BSP = 1 << 8;
+        const X2APIC = 1 << 10;
+        const ENABLE = 1 << 11;
+    }
+
+    /// `IA32_MISC_ENABLE`
+    struct MiscEnable = IA32_MISC_ENABLE {
+        const FAST_STRINGS = 1 << 0;
+        const AUTOMATIC_TCC = 1 << 3;
+        const PERF_MONITORING = 1 << 7;
+        const BTS_UNAVAILABLE = 1 << 11;
+        const PEBS_UNAVAILABLE = 1 << 12;
+        const ENHANCED_SPEEDSTEP = 1 << 16;
+        const MONITOR = 1 << 18;
+        /// Limit CPUID to leaf 2 for old operating systems
+        const LIMIT_CPUID = 1 << 22;
+        const XTPR_DISABLE = 1 << 23;
+        const XD_DISABLE = 1 << 34;
+    }
+
+    /// `IA32_SPEC_CTRL`
+    struct SpecCtrl = IA32_SPEC_CTRL {
+        const IBRS = 1 << 0;
+        const STIBP = 1 << 1;
+        const SSBD = 1 << 2;
+    }
+
+    /// `IA32_FEATURE_CONTROL`
+    struct FeatureControl = IA32_FEATURE_CONTROL {
+        /// No further writes until reset
+        const LOCK = 1 << 0;
+        const VMX_INSIDE_SMX = 1 << 1;
+        const VMX_OUTSIDE_SMX = 1 << 2;
+        const SGX_LAUNCH_CONTROL = 1 << 17;
+        const SGX = 1 << 18;
+        const LMCE = 1 << 20;
+    }
+}
+
+impl ApicBase {
+    const ADDR_MASK: u64 = 0x000ffffffffff000;
+
+    /// Returns the physical address of the xAPIC registers
+    pub fn base(self) -> u64 {
+        self.bits() & Self::ADDR_MASK
+    }
+}
+
 pub use msr_consts::*;
 #[rustfmt::skip]
 mod msr_consts {
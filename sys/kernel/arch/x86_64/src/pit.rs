@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Intel 8253/8254 Programmable Interval Timer
+//!
+//! The PIT is only used as a fixed-frequency reference for calibrating other timers.
+//! Channel 2 is used because its output can be polled through port 0x61 without
+//! taking an interrupt.
+
+use super::pio::{inb, outb};
+
+/// Frequency of the PIT's input clock, in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+
+const PORT_CHANNEL2: u16 = 0x42;
+const PORT_COMMAND: u16 = 0x43;
+const PORT_CONTROL: u16 = 0x61;
+
+const CONTROL_GATE2: u8 = 1 << 0;
+const CONTROL_SPEAKER: u8 = 1 << 1;
+const CONTROL_OUT2: u8 = 1 << 5;
+
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+const COMMAND_CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+
+/// Busy-wait for `us` microseconds
+///
+/// The caller should disable interrupts if the delay is being used as a reference.
+pub fn delay_us(us: u64) {
+    let mut ticks = us * FREQUENCY / 1_000_000;
+
+    while ticks > 0 {
+        let count = ticks.min(0xffff);
+        unsafe { oneshot(count as u16) };
+        ticks -= count;
+    }
+}
+
+/// Count down from `count` on channel 2 and wait for it to expire
+unsafe fn oneshot(count: u16) {
+    // Disconnect the speaker and drop the gate so the count doesn't start until reloaded.
+    let control = inb(PORT_CONTROL) & !(CONTROL_SPEAKER | CONTROL_GATE2);
+    outb(PORT_CONTROL, control);
+
+    outb(PORT_COMMAND, COMMAND_CHANNEL2_ONESHOT);
+    outb(PORT_CHANNEL2, count as u8);
+    outb(PORT_CHANNEL2, (count >> 8) as u8);
+
+    // A rising edge on the gate starts the count.
+    outb(PORT_CONTROL, control | CONTROL_GATE2);
+
+    while inb(PORT_CONTROL) & CONTROL_OUT2 == 0 {
+        core::hint::spin_loop();
+    }
+}
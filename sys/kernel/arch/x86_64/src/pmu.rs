@@ -0,0 +1,485 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Performance Monitoring
+//!
+//! Architectural performance monitoring, as enumerated by CPUID leaf 0xa. Each CPU has a
+//! small number of general-purpose counters, which are handed out as [`Counter`]s and
+//! only ever accessed from the CPU which owns them.
+//!
+//! One counter may instead be used for sampling: it is preloaded to overflow after a given
+//! number of events, raising a performance monitoring interrupt (delivered as an NMI so
+//! that code running with interrupts disabled is sampled too). The handler records the
+//! interrupted kernel RIP into a per-CPU ring buffer.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    marker::PhantomData,
+    ptr::{self, addr_of},
+    sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering},
+};
+
+use super::{lapic, msr, trap::TrapFrame};
+use crate::{cpu, sync::lazy::Lazy};
+
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// Writes to the counters through `IA32_A_PMCx` are not truncated to 32 bits
+const PERF_CAPABILITIES_FW_WRITE: u64 = 1 << 13;
+
+/// Architectural performance events
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    CoreCycles,
+    InstructionsRetired,
+    ReferenceCycles,
+    LlcReferences,
+    LlcMisses,
+    BranchesRetired,
+    BranchMissesRetired,
+    TopdownSlots,
+}
+
+impl Event {
+    pub const ALL: [Event; 8] = [
+        Event::CoreCycles,
+        Event::InstructionsRetired,
+        Event::ReferenceCycles,
+        Event::LlcReferences,
+        Event::LlcMisses,
+        Event::BranchesRetired,
+        Event::BranchMissesRetired,
+        Event::TopdownSlots,
+    ];
+
+    /// Returns the event select and unit mask of the event
+    const fn encoding(self) -> (u8, u8) {
+        match self {
+            Event::CoreCycles => (0x3c, 0x00),
+            Event::InstructionsRetired => (0xc0, 0x00),
+            Event::ReferenceCycles => (0x3c, 0x01),
+            Event::LlcReferences => (0x2e, 0x4f),
+            Event::LlcMisses => (0x2e, 0x41),
+            Event::BranchesRetired => (0xc4, 0x00),
+            Event::BranchMissesRetired => (0xc5, 0x00),
+            Event::TopdownSlots => (0xa4, 0x01),
+        }
+    }
+}
+
+/// Which privilege levels to count events in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    Kernel,
+    User,
+    All,
+}
+
+impl Mode {
+    const fn evtsel_bits(self) -> u64 {
+        match self {
+            Mode::Kernel => EVTSEL_OS,
+            Mode::User => EVTSEL_USR,
+            Mode::All => EVTSEL_OS | EVTSEL_USR,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The processor has no architectural performance monitoring
+    Unsupported,
+    /// The event is not supported by this processor
+    UnsupportedEvent(Event),
+    /// All counters on this CPU are in use
+    NoCounters,
+    /// This CPU is already sampling
+    Busy,
+}
+
+#[derive(Debug)]
+pub struct PmuInfo {
+    pub version:      u8,
+    pub num_counters: u32,
+    pub width:        u32,
+    pub num_fixed:    u32,
+    events:           u32,
+    full_width:       bool,
+}
+
+static PMU_INFO: Lazy<Option<PmuInfo>> = Lazy::new(PmuInfo::detect);
+
+impl PmuInfo {
+    fn detect() -> Option<PmuInfo> {
+        let leaf = unsafe { __cpuid_count(0xa, 0) };
+        let version = leaf.eax as u8;
+        let num_counters = (leaf.eax >> 8) & 0xff;
+        if version == 0 || num_counters == 0 {
+            log::info!("pmu: no architectural performance monitoring");
+            return None;
+        }
+
+        // EBX is a bit vector of *unavailable* events, of the length given in EAX.
+        let vector_len = (leaf.eax >> 24) & 0xff;
+        let events = !leaf.ebx & (1u32 << vector_len.min(31)).wrapping_sub(1);
+
+        // `IA32_PERF_CAPABILITIES` is only present if CPUID says so.
+        let has_perf_capabilities = unsafe { __cpuid(1).ecx } & (1 << 15) != 0;
+        let full_width = has_perf_capabilities
+            && msr::rdmsr_safe(msr::IA32_PERF_CAPABILITIES).unwrap_or(0)
+                & PERF_CAPABILITIES_FW_WRITE
+                != 0;
+
+        let info = PmuInfo {
+            version,
+            num_counters: num_counters.min(8),
+            width: (leaf.eax >> 16) & 0xff,
+            num_fixed: leaf.edx & 0x1f,
+            events,
+            full_width,
+        };
+        log::info!(
+            "pmu: version {}, {} counters ({}-bit), {} fixed",
+            info.version,
+            info.num_counters,
+            info.width,
+            info.num_fixed,
+        );
+
+        Some(info)
+    }
+
+    /// Returns `true` if `event` can be counted
+    pub fn supports(&self, event: Event) -> bool {
+        self.events & 1 << event as u32 != 0
+    }
+
+    fn mask(&self) -> u64 {
+        (1 << self.width) - 1
+    }
+}
+
+/// Returns information about the performance monitoring unit, if there is one
+pub fn info() -> Option<&'static PmuInfo> {
+    PMU_INFO.as_ref()
+}
+
+fn evtsel(index: u32) -> u32 {
+    msr::IA32_PERFEVTSEL0 + index
+}
+
+unsafe fn write_counter(info: &PmuInfo, index: u32, value: u64) {
+    match info.full_width {
+        true => msr::wrmsr(msr::IA32_A_PMC0 + index, value & info.mask()),
+        // Legacy writes sign-extend bit 31, so only 31-bit values can be written.
+        false => msr::wrmsr(msr::IA32_PMC0 + index, value & 0xffff_ffff),
+    }
+}
+
+/// Per-CPU performance monitoring state
+///
+/// The NMI handler may interrupt any code using this, so it is only ever accessed through
+/// atomics.
+pub struct PmuCpu {
+    /// Bitmap of allocated counters
+    in_use:   AtomicU32,
+    /// Sampling state, or null if this CPU is not sampling
+    sampling: AtomicPtr<Sampling>,
+}
+
+impl PmuCpu {
+    pub(super) const fn new() -> PmuCpu {
+        PmuCpu {
+            in_use:   AtomicU32::new(0),
+            sampling: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+fn this_pmu() -> &'static PmuCpu {
+    unsafe { &*addr_of!((*cpu::this_cpu()).md_data.pmu) }
+}
+
+/// Reset the counters of this CPU
+pub(super) unsafe fn init() {
+    let Some(info) = info() else {
+        return;
+    };
+
+    for index in 0..info.num_counters {
+        msr::wrmsr(evtsel(index), 0);
+        write_counter(info, index, 0);
+    }
+
+    if info.version >= 2 {
+        // The counters must also be enabled globally, the event select enables each one.
+        let mask = (1 << info.num_counters) - 1;
+        msr::wrmsr(msr::IA32_PERF_GLOBAL_OVF_CTRL, msr::rdmsr(msr::IA32_PERF_GLOBAL_STATUS));
+        msr::wrmsr(msr::IA32_PERF_GLOBAL_CTRL, mask);
+    }
+}
+
+fn alloc_counter(info: &PmuInfo, event: Event) -> Result<u32, Error> {
+    if !info.supports(event) {
+        return Err(Error::UnsupportedEvent(event));
+    }
+
+    let pmu = this_pmu();
+    let index = (!pmu.in_use.load(Ordering::Relaxed)).trailing_zeros();
+    if index >= info.num_counters {
+        return Err(Error::NoCounters);
+    }
+    pmu.in_use.fetch_or(1 << index, Ordering::Relaxed);
+
+    Ok(index)
+}
+
+fn free_counter(index: u32) {
+    unsafe { msr::wrmsr(evtsel(index), 0) };
+    this_pmu().in_use.fetch_and(!(1 << index), Ordering::Relaxed);
+}
+
+/// A counter on the current CPU
+///
+/// Counters must be read and dropped on the CPU which allocated them.
+pub struct Counter {
+    index:     u32,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Counter {
+    /// Start counting `event` on the current CPU
+    pub fn new(event: Event, mode: Mode) -> Result<Counter, Error> {
+        let info = info().ok_or(Error::Unsupported)?;
+        let index = alloc_counter(info, event)?;
+
+        let (select, umask) = event.encoding();
+        unsafe {
+            write_counter(info, index, 0);
+            msr::wrmsr(
+                evtsel(index),
+                EVTSEL_EN | mode.evtsel_bits() | (umask as u64) << 8 | select as u64,
+            );
+        }
+
+        Ok(Counter {
+            index,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Returns the number of events counted so far
+    pub fn read(&self) -> u64 {
+        unsafe { msr::rdmsr(msr::IA32_PMC0 + self.index) }
+    }
+
+    /// Reset the count to zero
+    pub fn reset(&self) {
+        unsafe { write_counter(info().unwrap(), self.index, 0) };
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        free_counter(self.index);
+    }
+}
+
+/*
+ * Sampling
+ */
+
+const SAMPLE_BUFFER_LEN: usize = 4096;
+
+/// Ring buffer of sampled RIPs
+///
+/// Written only by the PMI handler of the owning CPU, and read only by the same CPU.
+struct SampleBuffer {
+    /// Total number of samples written
+    head:    AtomicUsize,
+    /// Total number of samples consumed
+    tail:    AtomicUsize,
+    /// Samples dropped because the buffer was full
+    dropped: AtomicUsize,
+    rips:    [AtomicUsize; SAMPLE_BUFFER_LEN],
+}
+
+struct Sampling {
+    index:  u32,
+    period: u64,
+    buffer: Box<SampleBuffer>,
+}
+
+/// Start sampling the kernel on the current CPU, once every `period` occurrences of `event`
+pub fn start_sampling(event: Event, period: u64) -> Result<(), Error> {
+    let info = info().ok_or(Error::Unsupported)?;
+    if !this_pmu().sampling.load(Ordering::Relaxed).is_null() {
+        return Err(Error::Busy);
+    }
+
+    let index = alloc_counter(info, event)?;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: AtomicUsize = AtomicUsize::new(0);
+    let buffer = Box::new(SampleBuffer {
+        head:    AtomicUsize::new(0),
+        tail:    AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+        rips:    [EMPTY; SAMPLE_BUFFER_LEN],
+    });
+
+    // Without full-width writes, the period is limited by the sign extension of bit 31.
+    let period = match info.full_width {
+        true => period.clamp(1, info.mask() >> 1),
+        false => period.clamp(1, 0x7fff_ffff),
+    };
+
+    let sampling = Box::new(Sampling {
+        index,
+        period,
+        buffer,
+    });
+
+    // The sampling state must be published before the counter can raise a PMI.
+    let token = crate::trap::disable();
+    this_pmu().sampling.store(Box::into_raw(sampling), Ordering::Release);
+
+    let (select, umask) = event.encoding();
+    unsafe {
+        write_counter(info, index, period.wrapping_neg());
+        lapic::unmask_pmi();
+        msr::wrmsr(
+            evtsel(index),
+            EVTSEL_EN | EVTSEL_INT | EVTSEL_OS | (umask as u64) << 8 | select as u64,
+        );
+    }
+    crate::trap::enable(token);
+
+    Ok(())
+}
+
+/// Stop sampling on the current CPU
+///
+/// Samples which have not been read are discarded.
+pub fn stop_sampling() {
+    let token = crate::trap::disable();
+    let pmu = this_pmu();
+    let sampling = pmu.sampling.load(Ordering::Relaxed);
+    if !sampling.is_null() {
+        // Quiesce the counter before forgetting about it. A PMI arriving once the sampling
+        // state is gone would be taken for an unknown NMI.
+        let index = unsafe { (*sampling).index };
+        unsafe {
+            msr::wrmsr(evtsel(index), 0);
+            lapic::mask_pmi();
+            if info().is_some_and(|info| info.version >= 2) {
+                msr::wrmsr(msr::IA32_PERF_GLOBAL_OVF_CTRL, 1 << index);
+            }
+        }
+
+        // The NMI handler runs on this CPU, so it cannot be using the state any more.
+        pmu.sampling.store(ptr::null_mut(), Ordering::Relaxed);
+        drop(unsafe { Box::from_raw(sampling) });
+        free_counter(index);
+    }
+    crate::trap::enable(token);
+}
+
+/// Consume the samples recorded on the current CPU
+///
+/// Returns the number of samples which were dropped since the last call because the buffer
+/// was full.
+pub fn drain_samples(mut f: impl FnMut(usize)) -> usize {
+    let sampling = this_pmu().sampling.load(Ordering::Acquire);
+    let Some(sampling) = (unsafe { sampling.as_ref() }) else {
+        return 0;
+    };
+    let buffer = &*sampling.buffer;
+
+    let head = buffer.head.load(Ordering::Acquire);
+    let mut tail = buffer.tail.load(Ordering::Relaxed);
+    while tail != head {
+        f(buffer.rips[tail % SAMPLE_BUFFER_LEN].load(Ordering::Relaxed));
+        tail = tail.wrapping_add(1);
+    }
+    buffer.tail.store(tail, Ordering::Release);
+
+    buffer.dropped.swap(0, Ordering::Relaxed)
+}
+
+/// Handle an NMI which may have been raised by the sampling counter
+///
+/// Returns `true` if the NMI was a performance monitoring interrupt.
+pub(super) fn handle_nmi(frame: &TrapFrame) -> bool {
+    let Some(info) = info() else {
+        return false;
+    };
+    let sampling = this_pmu().sampling.load(Ordering::Acquire);
+    let Some(sampling) = (unsafe { sampling.as_ref() }) else {
+        return false;
+    };
+
+    let overflowed = unsafe {
+        if info.version >= 2 {
+            msr::rdmsr(msr::IA32_PERF_GLOBAL_STATUS) & 1 << sampling.index != 0
+        } else {
+            // The counter was preloaded with a negative value and is now small if it wrapped.
+            msr::rdmsr(msr::IA32_PMC0 + sampling.index) < info.mask() >> 1
+        }
+    };
+    if !overflowed {
+        return false;
+    }
+
+    // Only kernel samples are interesting.
+    if frame.cs & 3 == 0 {
+        let buffer = &*sampling.buffer;
+        let head = buffer.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(buffer.tail.load(Ordering::Acquire)) < SAMPLE_BUFFER_LEN {
+            buffer.rips[head % SAMPLE_BUFFER_LEN].store(frame.rip, Ordering::Relaxed);
+            buffer.head.store(head.wrapping_add(1), Ordering::Release);
+        } else {
+            buffer.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    unsafe {
+        write_counter(info, sampling.index, sampling.period.wrapping_neg());
+        if info.version >= 2 {
+            msr::wrmsr(msr::IA32_PERF_GLOBAL_OVF_CTRL, 1 << sampling.index);
+        }
+    }
+    lapic::unmask_pmi();
+
+    true
+}
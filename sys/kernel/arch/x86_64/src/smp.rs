@@ -0,0 +1,204 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Multiprocessor Startup
+//!
+//! Application processors are started through the bootloader, which enumerates them from
+//! the MADT and parks them in long mode until we hand them an entry point. ACPI is not
+//! available this early, so the MADT is only consulted later, by [`check_madt()`], to catch
+//! processors the bootloader did not report.
+
+use core::{
+    mem::MaybeUninit,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use super::{cpu, lapic, mce};
+use crate::{
+    cpu::{self as mi_cpu, Cpu, MAX_CPUS},
+    drivers::acpi::madt::{self, Madt, MadtEntry},
+    time,
+};
+
+static SMP_REQUEST: limine::SmpRequest = limine::SmpRequest::new(limine::SmpRequestFlags::X2APIC);
+
+/// Number of CPUs which have finished initialization, including the BSP
+///
+/// [`STARTUP_CLOSED`] is set once the BSP stops waiting, after which no more CPUs may
+/// come online.
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+const STARTUP_CLOSED: usize = 1 << (usize::BITS - 1);
+
+/// How long to wait for the APs to come online before continuing without them
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Start all application processors and wait for them to come online
+pub fn init() {
+    let Some(resp) = SMP_REQUEST.response() else {
+        log::warn!("no smp response from the bootloader, running uniprocessor");
+        return;
+    };
+
+    let bsp_lapic_id = resp.bsp_lapic_id();
+    let mut num_cpus = 1;
+
+    for info in resp.cpus() {
+        if info.lapic_id() == bsp_lapic_id {
+            continue;
+        }
+        if num_cpus == MAX_CPUS {
+            log::warn!("ignoring cpus beyond the first {MAX_CPUS}");
+            break;
+        }
+
+        let cpu = Box::leak(Box::new(MaybeUninit::<Cpu>::uninit())).as_mut_ptr();
+        unsafe {
+            addr_of_mut!((*cpu).cpu_id).write(num_cpus);
+            // The IDT is live as soon as the AP loads it, give it its own IST stacks first.
+            cpu::alloc_stacks(cpu);
+        }
+        num_cpus += 1;
+
+        info.start(ap_entry, cpu as usize);
+    }
+
+    let deadline = time::nanotime() + STARTUP_TIMEOUT.as_nanos() as u64;
+    while CPUS_ONLINE.load(Ordering::Acquire) < num_cpus && time::nanotime() < deadline {
+        core::hint::spin_loop();
+    }
+
+    // Any AP which has not checked in by now is left halted.
+    let online = CPUS_ONLINE.fetch_or(STARTUP_CLOSED, Ordering::AcqRel);
+    if online < num_cpus {
+        log::warn!("{} cpus failed to start", num_cpus - online);
+    }
+
+    log::info!("{online} cpus online");
+    report_topology();
+}
+
+/// Log the number of packages, cores and threads, and the caches of the boot CPU
+fn report_topology() {
+    let mut cores = mi_cpu::cpus()
+        .map(|cpu| {
+            let topology = cpu.identity.topology();
+            (topology.package, topology.die, topology.core)
+        })
+        .collect::<Vec<_>>();
+    cores.sort_unstable();
+    cores.dedup();
+
+    let mut packages = cores.iter().map(|&(package, _, _)| package).collect::<Vec<_>>();
+    packages.dedup();
+
+    log::info!(
+        "topology: {} packages, {} cores, {} threads",
+        packages.len(),
+        cores.len(),
+        mi_cpu::cpus().count(),
+    );
+
+    if let Some(bsp) = mi_cpu::get(0) {
+        for cache in bsp.identity.caches() {
+            log::info!("cache: {cache}");
+        }
+    }
+}
+
+/// Returns the number of CPUs which have been brought online
+pub fn num_cpus() -> usize {
+    CPUS_ONLINE.load(Ordering::Acquire) & !STARTUP_CLOSED
+}
+
+/// Warn about processors the MADT reports which were not started
+pub(super) fn check_madt(madt: &Madt) {
+    let enabled = madt
+        .entries()
+        .filter(|entry| match entry {
+            MadtEntry::LocalApic { flags, .. } => flags & madt::LOCAL_APIC_ENABLED != 0,
+            _ => false,
+        })
+        .count();
+
+    if enabled != num_cpus() {
+        log::warn!(
+            "madt reports {enabled} enabled processors, but {} are online",
+            num_cpus()
+        );
+    }
+}
+
+extern "C" fn ap_entry(info: &limine::SmpInfo) -> ! {
+    let cpu = info.argument() as *mut Cpu;
+
+    unsafe {
+        cpu::early_init(cpu);
+        lapic::init();
+        mce::start_polling();
+        cpu::init_stacks(cpu);
+
+        // The bootloader's stack lives in reclaimable memory, move to our own before
+        // announcing that we're online.
+        asm!(
+            "
+                mov     rsp, {stack}
+                xor     ebp, ebp
+                call    {main}
+                ud2
+            ",
+            stack = in(reg) cpu::boot_stack_top(cpu).0,
+            main = sym ap_main,
+            in("rdi") cpu,
+            options(noreturn),
+        );
+    }
+}
+
+extern "C" fn ap_main(cpu: *mut Cpu) -> ! {
+    let (cpu_id, apic_id, topology) = unsafe {
+        let identity = &(*cpu).md_identity;
+        ((*cpu).cpu_id, identity.apic_id(), *identity.topology())
+    };
+    let checked_in = CPUS_ONLINE.fetch_update(Ordering::AcqRel, Ordering::Acquire, |online| {
+        (online & STARTUP_CLOSED == 0).then_some(online + 1)
+    });
+    if checked_in.is_err() {
+        log::warn!("cpu{cpu_id} came up after the bsp stopped waiting, halting it");
+        unsafe { mi_cpu::unregister(cpu_id) };
+        super::hcf();
+    }
+
+    log::info!("cpu{cpu_id} online, apic id {apic_id}, {topology}");
+
+    super::idle();
+}
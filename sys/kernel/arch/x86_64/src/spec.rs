@@ -0,0 +1,320 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Speculative Execution Mitigations
+//!
+//! Which vulnerabilities apply is decided from CPUID and `IA32_ARCH_CAPABILITIES`, and the
+//! available controls are enabled to match. The choice can be overridden on the command
+//! line:
+//!
+//! - `mitigations=off|auto|force` sets the default for everything. `force` enables every
+//!   supported control, even on processors which claim not to be affected.
+//! - `spectre_v2=off|auto|force` and `spec_store_bypass=off|auto|force` override the
+//!   default for a single vulnerability.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    fmt,
+};
+
+use super::msr::{self, Msr, SpecCtrl};
+use crate::{cmdline, sync::lazy::Lazy};
+
+const PRED_CMD_IBPB: u64 = 1 << 0;
+
+const ARCH_CAP_RDCL_NO: u64 = 1 << 0;
+const ARCH_CAP_IBRS_ALL: u64 = 1 << 1;
+const ARCH_CAP_SSB_NO: u64 = 1 << 4;
+const ARCH_CAP_MDS_NO: u64 = 1 << 5;
+
+/// AMD's SSBD control for processors without `IA32_SPEC_CTRL.SSBD`
+const MSR_AMD64_VIRT_SPEC_CTRL: u32 = 0xc001011f;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Setting {
+    Off,
+    Auto,
+    Force,
+}
+
+impl Setting {
+    fn from_cmdline(key: &str, default: Setting) -> Setting {
+        Self::parse(key, cmdline::get(key), default)
+    }
+
+    fn parse(key: &str, value: Option<&str>, default: Setting) -> Setting {
+        match value {
+            None => default,
+            Some("off") => Setting::Off,
+            Some("auto") => Setting::Auto,
+            Some("force" | "on") => Setting::Force,
+            Some(value) => {
+                log::warn!("spec: unknown value `{value}` for `{key}`, using {default:?}");
+                default
+            }
+        }
+    }
+
+    fn enable(self, affected: bool) -> bool {
+        match self {
+            Setting::Off => false,
+            Setting::Auto => affected,
+            Setting::Force => true,
+        }
+    }
+}
+
+/// Speculation controls implemented by the processor
+#[derive(Debug, Default)]
+struct Controls {
+    spec_ctrl:  bool,
+    ibrs:       bool,
+    ibpb:       bool,
+    stibp:      bool,
+    ssbd:       bool,
+    virt_ssbd:  bool,
+    md_clear:   bool,
+    arch_caps:  u64,
+    amd_ssb_no: bool,
+    is_amd:     bool,
+}
+
+impl Controls {
+    fn detect() -> Controls {
+        let mut controls = Controls::default();
+
+        let vendor = unsafe { __cpuid(0) };
+        let vendor_id = [vendor.ebx, vendor.edx, vendor.ecx];
+        // "AuthenticAMD" or "HygonGenuine"
+        controls.is_amd = vendor_id == [0x68747541, 0x69746e65, 0x444d4163]
+            || vendor_id == [0x6f677948, 0x656e6975, 0x6e65476e];
+
+        if vendor.eax >= 7 {
+            let edx = unsafe { __cpuid_count(7, 0).edx };
+            controls.md_clear = edx & (1 << 10) != 0;
+            controls.spec_ctrl = edx & (1 << 26) != 0;
+            controls.ibrs = controls.spec_ctrl;
+            controls.ibpb = controls.spec_ctrl;
+            controls.stibp = edx & (1 << 27) != 0;
+            controls.ssbd = edx & (1 << 31) != 0;
+            if edx & (1 << 29) != 0 {
+                controls.arch_caps = msr::rdmsr_safe(msr::IA32_ARCH_CAPABILITIES).unwrap_or(0);
+            }
+        }
+
+        if unsafe { __cpuid(0x80000000).eax } >= 0x80000008 {
+            let ebx = unsafe { __cpuid(0x80000008).ebx };
+            controls.ibpb |= ebx & (1 << 12) != 0;
+            controls.ibrs |= ebx & (1 << 14) != 0;
+            controls.stibp |= ebx & (1 << 15) != 0;
+            controls.ssbd |= ebx & (1 << 24) != 0;
+            controls.virt_ssbd = ebx & (1 << 25) != 0;
+            controls.amd_ssb_no = ebx & (1 << 26) != 0;
+            controls.spec_ctrl |= controls.ibrs || controls.stibp || controls.ssbd;
+        }
+
+        controls
+    }
+
+    fn has_arch_cap(&self, bit: u64) -> bool {
+        self.arch_caps & bit != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Status {
+    NotAffected,
+    Vulnerable,
+    Mitigated(&'static str),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::NotAffected => f.write_str("not affected"),
+            Status::Vulnerable => f.write_str("vulnerable"),
+            Status::Mitigated(how) => write!(f, "mitigated ({how})"),
+        }
+    }
+}
+
+/// The mitigations chosen at boot
+struct Policy {
+    /// Value of `IA32_SPEC_CTRL` on every CPU
+    spec_ctrl: SpecCtrl,
+    /// Enable SSBD through AMD's `VIRT_SPEC_CTRL` instead
+    virt_ssbd: bool,
+    /// Flush indirect branch predictions when switching address spaces
+    ibpb:      bool,
+    report:    [(&'static str, Status); 5],
+}
+
+static POLICY: Lazy<Policy> = Lazy::new(Policy::decide);
+
+impl Policy {
+    fn decide() -> Policy {
+        let controls = Controls::detect();
+        let default = Setting::from_cmdline("mitigations", Setting::Auto);
+
+        let mut spec_ctrl = SpecCtrl::empty();
+        let mut virt_ssbd = false;
+        let mut ibpb = false;
+
+        // Meltdown requires page table isolation, which we do not implement.
+        let meltdown = match controls.is_amd || controls.has_arch_cap(ARCH_CAP_RDCL_NO) {
+            true => Status::NotAffected,
+            false => Status::Vulnerable,
+        };
+
+        // Every processor which speculates is affected by bounds check bypass, the mitigation
+        // is in the code itself.
+        let spectre_v1 = Status::Vulnerable;
+
+        let spectre_v2 = {
+            let setting = Setting::from_cmdline("spectre_v2", default);
+            if !setting.enable(true) {
+                Status::Vulnerable
+            } else if controls.has_arch_cap(ARCH_CAP_IBRS_ALL) {
+                spec_ctrl |= SpecCtrl::IBRS;
+                ibpb = controls.ibpb;
+                Status::Mitigated("enhanced IBRS")
+            } else if controls.ibrs {
+                // Without enhanced IBRS, it must stay set for all of kernel execution. We never
+                // clear it on return to user mode, which costs performance but is simple.
+                spec_ctrl |= SpecCtrl::IBRS;
+                if controls.stibp {
+                    spec_ctrl |= SpecCtrl::STIBP;
+                }
+                ibpb = controls.ibpb;
+                Status::Mitigated("IBRS, always on")
+            } else if controls.ibpb {
+                ibpb = true;
+                Status::Mitigated("IBPB only")
+            } else {
+                Status::Vulnerable
+            }
+        };
+
+        let spec_store_bypass = {
+            let affected = !controls.has_arch_cap(ARCH_CAP_SSB_NO) && !controls.amd_ssb_no;
+            let setting = Setting::from_cmdline("spec_store_bypass", default);
+            if !setting.enable(affected) {
+                match affected {
+                    true => Status::Vulnerable,
+                    false => Status::NotAffected,
+                }
+            } else if controls.ssbd {
+                spec_ctrl |= SpecCtrl::SSBD;
+                Status::Mitigated("SSBD")
+            } else if controls.virt_ssbd {
+                virt_ssbd = true;
+                Status::Mitigated("SSBD via VIRT_SPEC_CTRL")
+            } else {
+                Status::Vulnerable
+            }
+        };
+
+        // Clearing the buffers requires VERW on every return to user mode, not done yet.
+        let mds = match controls.is_amd || controls.has_arch_cap(ARCH_CAP_MDS_NO) {
+            true => Status::NotAffected,
+            false => Status::Vulnerable,
+        };
+        if mds == Status::Vulnerable && !controls.md_clear {
+            log::debug!("spec: processor does not support MD_CLEAR");
+        }
+
+        if !controls.spec_ctrl {
+            spec_ctrl = SpecCtrl::empty();
+        }
+
+        Policy {
+            spec_ctrl,
+            virt_ssbd,
+            ibpb,
+            report: [
+                ("meltdown", meltdown),
+                ("spectre_v1", spectre_v1),
+                ("spectre_v2", spectre_v2),
+                ("spec_store_bypass", spec_store_bypass),
+                ("mds", mds),
+            ],
+        }
+    }
+}
+
+/// Apply the mitigations on this CPU
+///
+/// The first call decides on the policy, which is the same for every CPU.
+pub(super) unsafe fn init() {
+    let policy = &*POLICY;
+
+    if !policy.spec_ctrl.is_empty() {
+        policy.spec_ctrl.write();
+    }
+    if policy.virt_ssbd {
+        msr::wrmsr(MSR_AMD64_VIRT_SPEC_CTRL, SpecCtrl::SSBD.bits());
+    }
+}
+
+/// Log the status of each vulnerability
+pub fn report() {
+    for (name, status) in &POLICY.report {
+        log::info!("spec: {name}: {status}");
+    }
+}
+
+/// Called after switching to a different user address space
+#[inline]
+pub fn switch_address_space() {
+    if POLICY.ibpb {
+        unsafe { msr::wrmsr(msr::IA32_PRED_CMD, PRED_CMD_IBPB) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::test_cases!(setting_parse);
+
+    fn setting_parse() {
+        let parse = |value| Setting::parse("test", value, Setting::Auto);
+
+        assert_eq!(parse(None), Setting::Auto);
+        assert_eq!(parse(Some("off")), Setting::Off);
+        assert_eq!(parse(Some("auto")), Setting::Auto);
+        assert_eq!(parse(Some("on")), Setting::Force);
+        assert_eq!(parse(Some("force")), Setting::Force);
+        // Unknown values, including a bare flag, keep the default.
+        assert_eq!(parse(Some("")), Setting::Auto);
+        assert_eq!(parse(Some("yes")), Setting::Auto);
+        assert_eq!(Setting::parse("test", Some("bogus"), Setting::Off), Setting::Off);
+    }
+}
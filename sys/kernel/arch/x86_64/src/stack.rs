@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Kernel Stacks
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{
+    hat::{PageSize, KERNEL_HAT},
+    memtype::CacheMode,
+};
+use crate::{
+    util::pow2,
+    vm::{self, page::PMAP_QUEUE, Prot, VirtAddr, PAGE_SIZE},
+};
+
+/// Region of the kernel address space reserved for stacks
+///
+/// Addresses are handed out linearly and never reused. Each stack is preceded by an unmapped
+/// guard page so that an overflow faults instead of silently corrupting whatever is below it.
+const STACK_REGION: Range<usize> = 0xffffff0000000000..0xffffff8000000000;
+
+static NEXT_STACK: AtomicUsize = AtomicUsize::new(STACK_REGION.start);
+
+/// A kernel stack with a guard page
+pub struct KernelStack {
+    /// Lowest mapped address of the stack
+    base: VirtAddr,
+    size: usize,
+}
+
+impl KernelStack {
+    /// Allocate a new stack of `size` bytes
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the stack region has been exhausted or memory for the stack
+    /// cannot be allocated.
+    pub fn new(size: usize) -> KernelStack {
+        debug_assert!(pow2::is_aligned!(size, PAGE_SIZE));
+
+        let guard = NEXT_STACK.fetch_add(PAGE_SIZE + size, Ordering::Relaxed);
+        assert!(
+            guard + PAGE_SIZE + size <= STACK_REGION.end,
+            "kernel stack region exhausted"
+        );
+        let base = VirtAddr(guard + PAGE_SIZE);
+
+        let mut hat = KERNEL_HAT.lock();
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let page = vm::Page::alloc(&mut PMAP_QUEUE.lock()).unwrap();
+            hat.map_pages(
+                base + offset,
+                page.addr,
+                PAGE_SIZE,
+                PageSize::Size4KiB,
+                Prot::READ | Prot::WRITE,
+                CacheMode::WriteBack,
+            )
+            .unwrap();
+        }
+
+        Self { base, size }
+    }
+
+    /// Returns the initial stack pointer
+    pub fn top(&self) -> VirtAddr {
+        self.base + self.size
+    }
+
+    /// Returns the range covered by the guard page below the stack
+    pub fn guard_page(&self) -> Range<VirtAddr> {
+        self.base - PAGE_SIZE..self.base
+    }
+}
@@ -0,0 +1,189 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! `SYSCALL`/`SYSRET` Fast System Call Entry
+//!
+//! System calls follow the System V convention: the call number is passed in `rax` and up
+//! to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result is returned in
+//! `rax`. `rcx` and `r11` are clobbered by the instruction itself.
+
+use memoffset::offset_of;
+
+use super::{
+    cpu::{CpuData, Tss, SEL_KCODE, SEL_UCODE, SEL_UCODE32, SEL_UDATA},
+    msr::{self, Msr},
+    trap::{TrapFrame, VEC_SYSCALL},
+};
+use crate::{
+    cpu::Cpu,
+    syscall,
+    trap::{self, Exception},
+};
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_AC: u64 = 1 << 18;
+
+/// Enable the `SYSCALL` instruction on this CPU
+pub(super) unsafe fn init() {
+    // `SYSCALL` loads CS from `STAR[47:32]` and SS from the following descriptor.
+    // `SYSRET` loads CS from `STAR[63:48] + 16` and SS from `STAR[63:48] + 8`, which is why
+    // the 32-bit user descriptors come before the 64-bit user code descriptor in the GDT.
+    let star = (SEL_UCODE32 as u64) << 48 | (SEL_KCODE as u64) << 32;
+    msr::wrmsr(msr::IA32_STAR, star);
+    msr::wrmsr(msr::IA32_LSTAR, x86_64_syscall_entry as usize as u64);
+
+    // Interrupts stay disabled until we're off of the user's stack, and the kernel expects
+    // the direction flag to be clear. Also make sure a user can't single-step into the
+    // kernel or turn off SMAP.
+    msr::wrmsr(msr::IA32_FMASK, RFLAGS_TF | RFLAGS_IF | RFLAGS_DF | RFLAGS_AC);
+
+    (msr::Efer::read() | msr::Efer::SCE).write();
+}
+
+extern "C" {
+    fn x86_64_syscall_entry();
+}
+
+/// Offset of the user stack pointer scratch slot in the `Cpu` structure
+const CPU_USER_RSP: usize = offset_of!(Cpu, md_data) + offset_of!(CpuData, user_rsp);
+
+/// Offset of `RSP0` in the `Cpu` structure
+const CPU_KERNEL_RSP: usize = offset_of!(Cpu, md_data)
+    + offset_of!(CpuData, tss)
+    + offset_of!(Tss, privileged_stack_table);
+
+global_asm!(
+    "
+    .pushsection .text.syscall, \"ax\", @progbits
+    .global x86_64_syscall_entry
+    .p2align 4
+x86_64_syscall_entry:
+    swapgs
+    mov     gs:[{CPU_USER_RSP}], rsp
+    mov     rsp, gs:[{CPU_KERNEL_RSP}]
+
+    // Build a `TrapFrame` which looks as if the system call was an interrupt. The user's
+    // `rip` and `rflags` were saved in `rcx` and `r11` by the processor.
+    push    {SEL_UDATA}
+    push    gs:[{CPU_USER_RSP}]
+    push    r11
+    push    {SEL_UCODE}
+    push    rcx
+    push    0
+    push    {VEC_SYSCALL}
+    push    r15
+    push    r14
+    push    r13
+    push    r12
+    push    r11
+    push    r10
+    push    r9
+    push    r8
+    push    rbp
+    push    rdi
+    push    rsi
+    push    rdx
+    push    rcx
+    push    rbx
+    push    rax
+
+    mov     rdi, rsp
+    sti
+    call    x86_64_syscall_handler
+    cli
+
+2:
+    // `SYSRET` raises #GP if the return address is non-canonical, but does so *after*
+    // it is too late: in ring 0, on the user's stack and with the user's GS base. Never
+    // let it see such an address. The check assumes a 48-bit address space, which is as
+    // much of the user half as we ever map.
+    mov     rcx, [rsp + {FRAME_RIP}]
+    mov     r11, rcx
+    shl     r11, 16
+    sar     r11, 16
+    cmp     r11, rcx
+    jne     3f
+
+    pop     rax
+    pop     rbx
+    pop     rcx
+    pop     rdx
+    pop     rsi
+    pop     rdi
+    pop     rbp
+    pop     r8
+    pop     r9
+    pop     r10
+    pop     r11
+    pop     r12
+    pop     r13
+    pop     r14
+    pop     r15
+
+    // Discard the vector and error code, leaving the interrupt frame.
+    add     rsp, 16
+    mov     rcx, [rsp]
+    mov     r11, [rsp + 16]
+    mov     rsp, [rsp + 24]
+    swapgs
+    sysretq
+
+3:
+    mov     rdi, rsp
+    call    x86_64_syscall_bad_return
+    jmp     2b
+
+    .popsection
+    ",
+    CPU_USER_RSP = const CPU_USER_RSP,
+    CPU_KERNEL_RSP = const CPU_KERNEL_RSP,
+    SEL_UDATA = const SEL_UDATA,
+    SEL_UCODE = const SEL_UCODE,
+    VEC_SYSCALL = const VEC_SYSCALL,
+    FRAME_RIP = const offset_of!(TrapFrame, rip),
+);
+
+#[no_mangle]
+unsafe extern "C" fn x86_64_syscall_handler(frame: *mut TrapFrame) {
+    let frame = &mut *frame;
+
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = syscall::dispatch(frame.rax, &args) as usize;
+}
+
+/// Called with interrupts disabled when a system call would return to a non-canonical address
+///
+/// This is a fault of the user program, delivered as if the processor raised it.
+#[no_mangle]
+unsafe extern "C" fn x86_64_syscall_bad_return(frame: *mut TrapFrame) {
+    trap::exception(&mut *frame, Exception::ProtectionFault);
+}
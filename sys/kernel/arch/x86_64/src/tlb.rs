@@ -0,0 +1,481 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! TLB Invalidation
+//!
+//! After a translation is changed, every CPU which may have cached the old one must
+//! invalidate it. Each [`Hat`] tracks the CPUs it has been active on, and changes to it are
+//! followed by a [`shootdown()`], which invalidates the range locally and interrupts those
+//! CPUs to do the same. Translations in the kernel half are shared by every address space,
+//! so changes to them are sent to every CPU.
+//!
+//! Only one shootdown is in flight at a time. A CPU waiting to start one keeps servicing
+//! requests sent to it, so two CPUs shooting each other down with interrupts disabled cannot
+//! deadlock.
+//!
+//! [`Hat`]: super::hat::Hat
+
+use core::{
+    cell::UnsafeCell,
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use cpu_features::CpuFeat;
+use x86_64::control::Cr4;
+
+use super::{
+    cpu::CPU_FEATURES,
+    hat,
+    lapic::{self, IpiTarget},
+    trap::{register_handler, TrapFrame},
+};
+use crate::{
+    cpu::{self, MAX_CPUS},
+    trap,
+    vm::{PhysAddr, VirtAddr, PAGE_SIZE},
+};
+
+/// Vector of the TLB shootdown IPI
+const VECTOR_SHOOTDOWN: u8 = 0xf1;
+
+/// Ranges larger than this are flushed entirely, rather than page by page
+const MAX_INVLPG_PAGES: usize = 32;
+
+/// A set of CPUs, by `cpu_id`
+pub(super) struct CpuMask([AtomicU64; MAX_CPUS / 64]);
+
+impl CpuMask {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_WORD: AtomicU64 = AtomicU64::new(0);
+
+    pub(super) const fn new() -> CpuMask {
+        CpuMask([Self::EMPTY_WORD; MAX_CPUS / 64])
+    }
+
+    pub(super) fn insert(&self, cpu_id: usize) {
+        self.0[cpu_id / 64].fetch_or(1 << (cpu_id % 64), Ordering::AcqRel);
+    }
+
+    pub(super) fn remove(&self, cpu_id: usize) {
+        self.0[cpu_id / 64].fetch_and(!(1 << (cpu_id % 64)), Ordering::AcqRel);
+    }
+
+    pub(super) fn contains(&self, cpu_id: usize) -> bool {
+        self.0[cpu_id / 64].load(Ordering::Acquire) & 1 << (cpu_id % 64) != 0
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| word.load(Ordering::Acquire) == 0)
+    }
+}
+
+/// Address space whose translations are being invalidated
+#[derive(Clone, Copy)]
+pub(super) struct AddressSpace<'a> {
+    /// Physical address of the top-level table
+    pub(super) top_level:  PhysAddr,
+    /// Generation of the address space, from [`new_generation()`]
+    pub(super) generation: u64,
+    /// CPUs which may have cached translations from this address space
+    pub(super) active:     &'a CpuMask,
+}
+
+/*
+ * Local Invalidation
+ */
+
+fn read_cr3() -> usize {
+    let cr3;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+    cr3
+}
+
+const CR3_ADDR_MASK: usize = 0x000ffffffffff000;
+
+/// Invalidate the translation of `virt` in the current address space
+#[inline]
+pub(super) fn invlpg(virt: VirtAddr) {
+    unsafe { asm!("invlpg [{}]", in(reg) virt.0, options(nostack, preserves_flags)) };
+}
+
+#[derive(Clone, Copy)]
+#[repr(u64)]
+enum InvpcidKind {
+    Address = 0,
+    Context = 1,
+    AllIncludingGlobal = 2,
+}
+
+#[inline]
+fn invpcid(kind: InvpcidKind, pcid: usize, virt: VirtAddr) {
+    let descriptor = [pcid as u64, virt.0 as u64];
+    unsafe {
+        asm!(
+            "invpcid {}, [{}]",
+            in(reg) kind as u64,
+            in(reg) descriptor.as_ptr(),
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+fn has_invpcid() -> bool {
+    CPU_FEATURES[CpuFeat::INVPCID]
+}
+
+/// Invalidate all translations, for every PCID, including global ones
+pub(super) fn flush_everything() {
+    if has_invpcid() {
+        invpcid(InvpcidKind::AllIncludingGlobal, 0, VirtAddr(0));
+        return;
+    }
+
+    // Toggling CR4.PGE invalidates everything, whatever its current value.
+    let cr4 = Cr4::read();
+    unsafe {
+        (cr4 ^ Cr4::PGE).write();
+        cr4.write();
+    }
+}
+
+/// Invalidate `range` of the address space with top-level table `top_level` on the current CPU
+///
+/// Returns `false` if this CPU holds no translations from the address space, and need not be
+/// sent requests for it until it is switched to again.
+///
+/// Must be called with interrupts disabled, a shootdown arriving in the middle would modify
+/// the PCID assignments of this CPU under our feet.
+fn flush_local(
+    top_level: PhysAddr,
+    generation: u64,
+    range: &Range<VirtAddr>,
+    kernel: bool,
+) -> bool {
+    let num_pages = (range.end.0 - range.start.0) / PAGE_SIZE;
+    let single_pages = num_pages <= MAX_INVLPG_PAGES;
+    let pages = || (range.start.0..range.end.0).step_by(PAGE_SIZE).map(VirtAddr);
+
+    // `invlpg` only affects the current PCID, while kernel translations may be cached
+    // under all of them.
+    if kernel {
+        match single_pages && !hat::pcid_enabled() {
+            true => pages().for_each(invlpg),
+            false => flush_everything(),
+        }
+        return true;
+    }
+
+    let cr3 = read_cr3();
+    if cr3 & CR3_ADDR_MASK == top_level.0 {
+        match single_pages {
+            true => pages().for_each(invlpg),
+            // Reloading CR3 without the no-flush bit invalidates the current PCID.
+            false => unsafe {
+                asm!("mov cr3, {}", in(reg) cr3 & !CR3_NOFLUSH, options(nostack, preserves_flags));
+            },
+        }
+        return true;
+    }
+
+    // Without PCIDs, switching away from the address space flushed its translations.
+    if !hat::pcid_enabled() {
+        return false;
+    }
+
+    let pcids = unsafe { &mut (*cpu::this_cpu()).md_data.pcids };
+    let Some(pcid) = pcids.find(generation) else {
+        return false;
+    };
+
+    // Another PCID can only be targeted with `invpcid`. Without it, give the PCID up so it
+    // is flushed when the address space is switched to again.
+    if !has_invpcid() {
+        pcids.forget(pcid);
+        return false;
+    }
+
+    match single_pages {
+        true => pages().for_each(|virt| invpcid(InvpcidKind::Address, pcid, virt)),
+        false => invpcid(InvpcidKind::Context, pcid, VirtAddr(0)),
+    }
+    true
+}
+
+/*
+ * PCID Allocation
+ */
+
+/// Number of PCIDs used on each CPU
+///
+/// PCID 0 is left to the bootloader's page tables.
+const NUM_PCIDS: usize = 16;
+
+const CR3_NOFLUSH: usize = 1 << 63;
+
+/// Generation of the next address space, 0 marks a free PCID
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// Returns a generation number for a new address space
+///
+/// Generations are never reused, so a PCID tagged with one can only hold translations from
+/// that address space.
+pub(super) fn new_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// PCID assignments of a CPU
+///
+/// Each PCID is tagged with the generation of the address space it was last used for. When
+/// they run out, they are recycled round-robin, and a recycled PCID is flushed before the new
+/// address space uses it.
+pub(super) struct PcidCpu {
+    generations: [u64; NUM_PCIDS],
+    next_victim: usize,
+}
+
+impl PcidCpu {
+    pub(super) const fn new() -> PcidCpu {
+        PcidCpu {
+            generations: [0; NUM_PCIDS],
+            next_victim: 0,
+        }
+    }
+
+    /// Returns the PCID holding translations from `generation`
+    fn find(&self, generation: u64) -> Option<usize> {
+        let index = self.generations.iter().position(|&g| g == generation)?;
+        Some(index + 1)
+    }
+
+    /// Assign a PCID to `generation`, which may still hold another address space's
+    /// translations
+    fn assign(&mut self, generation: u64) -> usize {
+        let index = match self.generations.iter().position(|&g| g == 0) {
+            Some(index) => index,
+            None => {
+                let index = self.next_victim;
+                self.next_victim = (index + 1) % NUM_PCIDS;
+                index
+            }
+        };
+        self.generations[index] = generation;
+        index + 1
+    }
+
+    fn forget(&mut self, pcid: usize) {
+        self.generations[pcid - 1] = 0;
+    }
+}
+
+/// Load the address space with top-level table `top_level` on the current CPU
+///
+/// With PCIDs, translations of address spaces which still have a PCID on this CPU are kept,
+/// and no flush is necessary when switching back to them.
+///
+/// Returns `true` if CR3 was reloaded, or `false` if the address space was already current.
+///
+/// # Safety
+///
+/// Interrupts must be disabled, and this CPU must be in the address space's active set.
+pub(super) unsafe fn switch_to(top_level: PhysAddr, generation: u64) -> bool {
+    let cr3 = if hat::pcid_enabled() {
+        let pcids = &mut (*cpu::this_cpu()).md_data.pcids;
+        match pcids.find(generation) {
+            Some(pcid) => top_level.0 | pcid | CR3_NOFLUSH,
+            None => {
+                let pcid = pcids.assign(generation);
+                match has_invpcid() {
+                    true => {
+                        invpcid(InvpcidKind::Context, pcid, VirtAddr(0));
+                        top_level.0 | pcid | CR3_NOFLUSH
+                    }
+                    // Loading CR3 without the no-flush bit invalidates the new PCID.
+                    false => top_level.0 | pcid,
+                }
+            }
+        }
+    } else {
+        top_level.0
+    };
+
+    if read_cr3() == cr3 & !CR3_NOFLUSH {
+        return false;
+    }
+    asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+    true
+}
+
+/*
+ * Shootdowns
+ */
+
+struct Request {
+    top_level:  PhysAddr,
+    generation: u64,
+    /// The [`AddressSpace::active`] set of the initiator, or null for kernel translations
+    active:     *const CpuMask,
+    range:      Range<VirtAddr>,
+}
+
+struct RequestCell(UnsafeCell<Request>);
+
+// Only written by the CPU holding `SHOOTDOWN_BUSY`, while no CPU is pending.
+unsafe impl Sync for RequestCell {}
+
+static REQUEST: RequestCell = RequestCell(UnsafeCell::new(Request {
+    top_level:  PhysAddr(0),
+    generation: 0,
+    active:     ptr::null(),
+    range:      VirtAddr(0)..VirtAddr(0),
+}));
+
+/// Held by the CPU initiating a shootdown
+static SHOOTDOWN_BUSY: AtomicBool = AtomicBool::new(false);
+
+/// CPUs which have not yet handled the current request
+static PENDING: CpuMask = CpuMask::new();
+
+fn this_cpu_id() -> usize {
+    unsafe { (*cpu::this_cpu()).cpu_id }
+}
+
+/// Set up the shootdown IPI
+pub(super) fn init() {
+    register_handler(VECTOR_SHOOTDOWN, |_: &mut TrapFrame| handle_pending());
+}
+
+/// Handle the current request, if it is pending on this CPU
+fn handle_pending() {
+    let token = trap::disable();
+    let cpu_id = this_cpu_id();
+    if PENDING.contains(cpu_id) {
+        let request = unsafe { &*REQUEST.0.get() };
+        let kernel = request.active.is_null();
+        let cached = flush_local(request.top_level, request.generation, &request.range, kernel);
+
+        // Stop sending requests here until the address space is switched to again.
+        if !kernel && !cached {
+            unsafe { (*request.active).remove(cpu_id) };
+        }
+
+        PENDING.remove(cpu_id);
+    }
+    trap::enable(token);
+}
+
+/// Invalidate `range` of `space` on every CPU which may have cached it
+///
+/// `kernel` must be set if the range is in the kernel half, in which case every CPU is
+/// interrupted.
+pub(super) fn shootdown(space: &AddressSpace, range: Range<VirtAddr>, kernel: bool) {
+    if range.is_empty() {
+        return;
+    }
+
+    let token = trap::disable();
+    let cpu_id = this_cpu_id();
+    if !flush_local(space.top_level, space.generation, &range, kernel) && !kernel {
+        space.active.remove(cpu_id);
+    }
+    trap::enable(token);
+
+    let is_target = |other: usize| other != cpu_id && (kernel || space.active.contains(other));
+    if !cpu::cpus().any(|cpu| is_target(cpu.cpu_id)) {
+        return;
+    }
+
+    while SHOOTDOWN_BUSY
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        handle_pending();
+        core::hint::spin_loop();
+    }
+
+    unsafe {
+        REQUEST.0.get().write(Request {
+            top_level: space.top_level,
+            generation: space.generation,
+            active: match kernel {
+                true => ptr::null(),
+                false => space.active,
+            },
+            range,
+        });
+    }
+
+    for cpu in cpu::cpus().filter(|cpu| is_target(cpu.cpu_id)) {
+        PENDING.insert(cpu.cpu_id);
+        lapic::send_ipi(IpiTarget::Apic(cpu.identity.apic_id()), VECTOR_SHOOTDOWN);
+    }
+
+    while !PENDING.is_empty() {
+        core::hint::spin_loop();
+    }
+
+    SHOOTDOWN_BUSY.store(false, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::test_cases!(pcid_recycling);
+
+    /// Assign more address spaces than there are PCIDs, forgetting one on the way
+    fn pcid_recycling() {
+        let mut pcids = PcidCpu::new();
+
+        // Every PCID but 0 is handed out before any is reused.
+        for generation in 1..=NUM_PCIDS as u64 {
+            assert_eq!(pcids.assign(generation), generation as usize);
+        }
+        for generation in 1..=NUM_PCIDS as u64 {
+            assert_eq!(pcids.find(generation), Some(generation as usize));
+        }
+
+        // PCIDs are recycled round-robin, taking them from the address spaces using them.
+        let next = NUM_PCIDS as u64 + 1;
+        assert_eq!(pcids.assign(next), 1);
+        assert_eq!(pcids.find(1), None);
+        assert_eq!(pcids.assign(next + 1), 2);
+        assert_eq!(pcids.find(2), None);
+
+        // A forgotten PCID is reused before the next victim.
+        pcids.forget(5);
+        assert_eq!(pcids.find(5), None);
+        assert_eq!(pcids.assign(next + 2), 5);
+        assert_eq!(pcids.assign(next + 3), 3);
+        assert_eq!(pcids.find(next), Some(1));
+        assert_eq!(pcids.find(next + 3), Some(3));
+    }
+}
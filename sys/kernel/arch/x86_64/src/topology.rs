@@ -0,0 +1,296 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! CPU Topology
+//!
+//! The APIC ID of each logical processor is split into fields identifying the package, die,
+//! core and SMT thread it belongs to. CPUID describes the width of each field, through leaf
+//! 0x1f or 0xb when available, through the AMD extended leaves otherwise, and through leaves
+//! 1 and 4 on old processors.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    fmt,
+};
+
+/// Location of a logical processor in the package, die, core and SMT hierarchy
+///
+/// Each ID is relative to the level above it, so two CPUs are SMT siblings if everything
+/// but `thread` is the same.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Topology {
+    pub package: u32,
+    pub die:     u32,
+    pub core:    u32,
+    pub thread:  u32,
+}
+
+impl Topology {
+    /// Returns `true` if both CPUs are threads of the same core
+    pub fn is_smt_sibling(&self, other: &Topology) -> bool {
+        self.package == other.package && self.die == other.die && self.core == other.core
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "package {} die {} core {} thread {}",
+            self.package, self.die, self.core, self.thread
+        )
+    }
+}
+
+/// Bit offsets of each field in the APIC ID
+///
+/// The core field spans `smt..die` and the die field spans `die..package`.
+#[derive(Debug)]
+struct Shifts {
+    smt:     u32,
+    die:     u32,
+    package: u32,
+}
+
+impl Shifts {
+    fn decode(&self, apic_id: u32) -> Topology {
+        let field = |lo: u32, hi: u32| {
+            apic_id.checked_shr(lo).unwrap_or(0) & mask(hi.saturating_sub(lo))
+        };
+        Topology {
+            package: apic_id.checked_shr(self.package).unwrap_or(0),
+            die:     field(self.die, self.package),
+            core:    field(self.smt, self.die),
+            thread:  field(0, self.smt),
+        }
+    }
+}
+
+fn mask(bits: u32) -> u32 {
+    1u32.checked_shl(bits).unwrap_or(0).wrapping_sub(1)
+}
+
+/// Returns the number of bits needed to hold `count` different IDs
+fn id_bits(count: u32) -> u32 {
+    count.max(1).next_power_of_two().trailing_zeros()
+}
+
+const LEVEL_SMT: u32 = 1;
+const LEVEL_DIE: u32 = 5;
+
+/// Parse the extended topology enumeration leaf, 0x1f or 0xb
+fn extended_topology(leaf: u32) -> Option<Shifts> {
+    // The leaf is not implemented if subleaf 0 reports no logical processors.
+    if unsafe { __cpuid_count(leaf, 0).ebx } == 0 {
+        return None;
+    }
+
+    let mut shifts = Shifts { smt: 0, die: 0, package: 0 };
+    let mut die = None;
+
+    for subleaf in 0.. {
+        let regs = unsafe { __cpuid_count(leaf, subleaf) };
+        let level_type = (regs.ecx >> 8) & 0xff;
+        if level_type == 0 {
+            break;
+        }
+
+        // The shift gives the width of this level and every level below it.
+        let shift = regs.eax & 0x1f;
+        match level_type {
+            LEVEL_SMT => shifts.smt = shift,
+            LEVEL_DIE => die = Some(shifts.package),
+            _ => {}
+        }
+        shifts.package = shift;
+    }
+
+    shifts.die = die.unwrap_or(shifts.package);
+    Some(shifts)
+}
+
+/// Use AMD's extended leaves, for processors without leaf 0xb
+fn amd_topology() -> Option<Shifts> {
+    if unsafe { __cpuid(0x80000000).eax } < 0x80000008 {
+        return None;
+    }
+
+    let ecx = unsafe { __cpuid(0x80000008).ecx };
+    let package = match (ecx >> 12) & 0xf {
+        0 => id_bits((ecx & 0xff) + 1),
+        bits => bits,
+    };
+
+    // Leaf 0x8000001e is only valid with the topology extensions.
+    let has_topoext = unsafe { __cpuid(0x80000001).ecx } & (1 << 22) != 0;
+    let smt = if has_topoext && unsafe { __cpuid(0x80000000).eax } >= 0x8000001e {
+        let threads_per_core = ((unsafe { __cpuid(0x8000001e).ebx } >> 8) & 0xff) + 1;
+        id_bits(threads_per_core)
+    } else {
+        0
+    };
+
+    Some(Shifts { smt, die: package, package })
+}
+
+/// Use leaves 1 and 4, which give the maximum number of threads and cores per package
+fn legacy_topology(max_leaf: u32) -> Shifts {
+    let leaf1 = unsafe { __cpuid(1) };
+    if leaf1.edx & (1 << 28) == 0 {
+        return Shifts { smt: 0, die: 0, package: 0 };
+    }
+
+    let threads_per_package = (leaf1.ebx >> 16) & 0xff;
+    let cores_per_package = match max_leaf >= 4 {
+        true => (unsafe { __cpuid_count(4, 0).eax } >> 26) + 1,
+        false => 1,
+    };
+
+    let package = id_bits(threads_per_package);
+    let smt = id_bits(threads_per_package / cores_per_package);
+    Shifts { smt, die: package, package }
+}
+
+fn is_amd() -> bool {
+    let vendor = unsafe { __cpuid(0) };
+    let vendor_id = [vendor.ebx, vendor.edx, vendor.ecx];
+    // "AuthenticAMD" or "HygonGenuine"
+    vendor_id == [0x68747541, 0x69746e65, 0x444d4163]
+        || vendor_id == [0x6f677948, 0x656e6975, 0x6e65476e]
+}
+
+/// Returns the topology of the current CPU, given its APIC ID
+pub(super) fn detect(apic_id: u32) -> Topology {
+    let max_leaf = unsafe { __cpuid(0).eax };
+
+    // Leaf 0x1f is a superset of 0xb which also describes dies.
+    let mut shifts = None;
+    if max_leaf >= 0x1f {
+        shifts = extended_topology(0x1f);
+    }
+    if shifts.is_none() && max_leaf >= 0xb {
+        shifts = extended_topology(0xb);
+    }
+    if shifts.is_none() && is_amd() {
+        shifts = amd_topology();
+    }
+
+    shifts
+        .unwrap_or_else(|| legacy_topology(max_leaf))
+        .decode(apic_id)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// Description of a cache, from CPUID leaf 4
+#[derive(Clone, Copy, Debug)]
+pub struct Cache {
+    pub level:     u8,
+    pub kind:      CacheKind,
+    /// Total size in bytes
+    pub size:      usize,
+    pub line_size: usize,
+    pub ways:      usize,
+    pub sets:      usize,
+    /// Maximum number of logical processors sharing this cache
+    pub shared_by: u32,
+}
+
+impl fmt::Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            CacheKind::Data => "d",
+            CacheKind::Instruction => "i",
+            CacheKind::Unified => "",
+        };
+        write!(
+            f,
+            "L{}{kind} {} KiB, {}-way, {} byte lines, shared by {}",
+            self.level,
+            self.size / 1024,
+            self.ways,
+            self.line_size,
+            self.shared_by,
+        )
+    }
+}
+
+pub(super) const MAX_CACHES: usize = 8;
+
+/// Returns the caches of the current CPU
+pub(super) fn caches() -> [Option<Cache>; MAX_CACHES] {
+    let mut caches = [None; MAX_CACHES];
+
+    // AMD reports the same information in leaf 0x8000001d, with the topology extensions.
+    let leaf = if is_amd() {
+        let has_topoext = unsafe { __cpuid(0x80000001).ecx } & (1 << 22) != 0;
+        if !has_topoext || unsafe { __cpuid(0x80000000).eax } < 0x8000001d {
+            return caches;
+        }
+        0x8000001d
+    } else {
+        if unsafe { __cpuid(0).eax } < 4 {
+            return caches;
+        }
+        4
+    };
+
+    for (subleaf, slot) in caches.iter_mut().enumerate() {
+        let regs = unsafe { __cpuid_count(leaf, subleaf as u32) };
+        let kind = match regs.eax & 0x1f {
+            1 => CacheKind::Data,
+            2 => CacheKind::Instruction,
+            3 => CacheKind::Unified,
+            _ => break,
+        };
+
+        let line_size = (regs.ebx & 0xfff) as usize + 1;
+        let partitions = ((regs.ebx >> 12) & 0x3ff) as usize + 1;
+        let ways = (regs.ebx >> 22) as usize + 1;
+        let sets = regs.ecx as usize + 1;
+
+        *slot = Some(Cache {
+            level: ((regs.eax >> 5) & 0x7) as u8,
+            kind,
+            size: ways * partitions * line_size * sets,
+            line_size,
+            ways,
+            sets,
+            shared_by: ((regs.eax >> 14) & 0xfff) + 1,
+        });
+    }
+
+    caches
+}
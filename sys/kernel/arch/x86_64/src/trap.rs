@@ -28,10 +28,30 @@
  * SPDX-License-Identifier: BSD-3-Clause
  */
 
-use crate::{arch, trap};
+use core::{
+    fmt,
+    mem::{self, size_of},
+    ops::Range,
+    ptr::addr_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{
+    cpu::{self, Ist, SEL_KCODE, SEL_UCODE, SEL_UDATA},
+    extable, lapic, lbr, mce, pmu,
+};
+use crate::{
+    arch,
+    sync::lazy::Lazy,
+    trap::{self, Access, Exception, PageFault},
+    vm::VirtAddr,
+};
+
+global_asm!(include_str!(concat!(env!("OUT_DIR"), "/arch/x86_64/src/trap.S")));
 
 impl trap::ArchTrap for arch::ThisArch {
     type DisableToken = usize;
+    type Frame = TrapFrame;
 
     fn disable() -> Self::DisableToken {
         let token;
@@ -54,4 +74,437 @@ impl trap::ArchTrap for arch::ThisArch {
             unsafe { asm!("sti", options(nomem, nostack, preserves_flags)) };
         }
     }
+
+    fn from_user(frame: &TrapFrame) -> bool {
+        frame.cs & 3 != 0
+    }
+
+    fn freeze_debug_state() {
+        #[cfg(feature = "debug_lbr")]
+        lbr::freeze();
+    }
+
+    fn dump_debug_state() {
+        #[cfg(feature = "debug_lbr")]
+        lbr::dump();
+    }
+}
+
+/// Saved state of an interrupted context
+///
+/// The layout of this structure must match the order in which the registers are pushed by
+/// the entry stubs in `trap.S`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrapFrame {
+    pub rax:    usize,
+    pub rbx:    usize,
+    pub rcx:    usize,
+    pub rdx:    usize,
+    pub rsi:    usize,
+    pub rdi:    usize,
+    pub rbp:    usize,
+    pub r8:     usize,
+    pub r9:     usize,
+    pub r10:    usize,
+    pub r11:    usize,
+    pub r12:    usize,
+    pub r13:    usize,
+    pub r14:    usize,
+    pub r15:    usize,
+    pub vector: usize,
+    pub error:  usize,
+    // Pushed by the processor.
+    pub rip:    usize,
+    pub cs:     usize,
+    pub rflags: usize,
+    pub rsp:    usize,
+    pub ss:     usize,
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // CR2 is only meaningful for page faults, but it is cheap to print and occasionally
+        // useful to see the last faulting address.
+        let cr2 = read_cr2();
+
+        writeln!(
+            f,
+            "vector {} ({}), error code {:#x}, cr2 {cr2:#018x}",
+            self.vector,
+            exception_name(self.vector),
+            self.error,
+        )?;
+        writeln!(
+            f,
+            "rax {:016x} rbx {:016x} rcx {:016x} rdx {:016x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "rsi {:016x} rdi {:016x} rbp {:016x} rsp {:016x}",
+            self.rsi, self.rdi, self.rbp, self.rsp
+        )?;
+        writeln!(
+            f,
+            "r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}",
+            self.r8, self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}",
+            self.r12, self.r13, self.r14, self.r15
+        )?;
+        write!(
+            f,
+            "rip {:016x} rfl {:016x} cs {:04x} ss {:04x}",
+            self.rip, self.rflags, self.cs, self.ss
+        )
+    }
+}
+
+const VEC_DE: usize = 0x00;
+const VEC_DB: usize = 0x01;
+const VEC_NMI: usize = 0x02;
+const VEC_BP: usize = 0x03;
+const VEC_UD: usize = 0x06;
+const VEC_DF: usize = 0x08;
+const VEC_TS: usize = 0x0a;
+const VEC_NP: usize = 0x0b;
+const VEC_SS: usize = 0x0c;
+const VEC_GP: usize = 0x0d;
+const VEC_PF: usize = 0x0e;
+const VEC_MF: usize = 0x10;
+const VEC_AC: usize = 0x11;
+const VEC_MC: usize = 0x12;
+const VEC_XM: usize = 0x13;
+
+const NUM_EXCEPTIONS: usize = 32;
+
+/// Pseudo-vector stored in the `TrapFrame` of a system call
+pub(super) const VEC_SYSCALL: usize = 0x100;
+
+fn exception_name(vector: usize) -> &'static str {
+    const NAMES: [&str; NUM_EXCEPTIONS] = [
+        "#DE divide error",
+        "#DB debug",
+        "NMI",
+        "#BP breakpoint",
+        "#OF overflow",
+        "#BR bound range exceeded",
+        "#UD invalid opcode",
+        "#NM device not available",
+        "#DF double fault",
+        "coprocessor segment overrun",
+        "#TS invalid TSS",
+        "#NP segment not present",
+        "#SS stack-segment fault",
+        "#GP general protection",
+        "#PF page fault",
+        "reserved",
+        "#MF x87 floating-point error",
+        "#AC alignment check",
+        "#MC machine check",
+        "#XM SIMD floating-point exception",
+        "#VE virtualization exception",
+        "#CP control protection",
+        "reserved",
+        "reserved",
+        "reserved",
+        "reserved",
+        "reserved",
+        "reserved",
+        "#HV hypervisor injection",
+        "#VC VMM communication",
+        "#SX security exception",
+        "reserved",
+    ];
+
+    match vector {
+        VEC_SYSCALL => "system call",
+        _ => NAMES.get(vector).copied().unwrap_or("interrupt"),
+    }
+}
+
+fn read_cr2() -> usize {
+    let cr2;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags)) };
+    cr2
+}
+
+/*
+ * Interrupt Descriptor Table
+ */
+
+/// Distance between the entry stubs in `x86_64_trap_stubs`
+const TRAP_STUB_SIZE: usize = 16;
+
+extern "C" {
+    static x86_64_trap_stubs: [u8; 0];
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Gate {
+    offset_lo:  u16,
+    selector:   u16,
+    ist:        u8,
+    flags:      u8,
+    offset_mid: u16,
+    offset_hi:  u32,
+    reserved:   u32,
+}
+
+impl Gate {
+    const NULL: Self = Self {
+        offset_lo:  0,
+        selector:   0,
+        ist:        0,
+        flags:      0,
+        offset_mid: 0,
+        offset_hi:  0,
+        reserved:   0,
+    };
+
+    /// Create a present, DPL0, 64-bit interrupt gate
+    const fn interrupt(handler: usize) -> Gate {
+        Self {
+            offset_lo:  handler as u16,
+            selector:   SEL_KCODE,
+            ist:        0,
+            flags:      0x8e,
+            offset_mid: (handler >> 16) as u16,
+            offset_hi:  (handler >> 32) as u32,
+            reserved:   0,
+        }
+    }
+
+    /// Deliver this interrupt on a stack from the Interrupt Stack Table
+    const fn with_ist(self, ist: Ist) -> Gate {
+        Self {
+            ist: ist as u8,
+            ..self
+        }
+    }
+}
+
+#[repr(C, align(16))]
+struct Idt([Gate; 256]);
+
+static IDT: Lazy<Idt> = Lazy::new(|| {
+    let stubs = unsafe { addr_of!(x86_64_trap_stubs) as usize };
+    let mut idt = Idt([Gate::NULL; 256]);
+
+    for (vector, gate) in idt.0.iter_mut().enumerate() {
+        *gate = Gate::interrupt(stubs + vector * TRAP_STUB_SIZE);
+    }
+
+    for (vector, ist) in [
+        (VEC_DB, Ist::Debug),
+        (VEC_NMI, Ist::Nmi),
+        (VEC_DF, Ist::DoubleFault),
+        (VEC_MC, Ist::MachineCheck),
+    ] {
+        idt.0[vector] = idt.0[vector].with_ist(ist);
+    }
+
+    idt
+});
+
+/// Load the IDT on the current CPU, building it first if necessary
+pub(super) unsafe fn load_idt() {
+    let idt: *const Idt = &*IDT;
+
+    asm!(
+        // See `cpu::early_init()` for an explanation of the descriptor layout.
+        "
+            sub     rsp, 16
+            mov     word ptr [rsp + 6], {idt_limit}
+            mov     [rsp + 8], {0:r}
+            lidt    [rsp + 6]
+            add     rsp, 16
+        ",
+        in(reg) idt,
+        idt_limit = const size_of::<Idt>() - 1,
+    );
+}
+
+const RFLAGS_RESERVED: usize = 1 << 1;
+const RFLAGS_IF: usize = 1 << 9;
+
+/// Drop to user mode at `entry`, with the stack pointer set to `stack`
+///
+/// The current stack is abandoned, and should be the one installed with
+/// [`cpu::set_kernel_stack()`]. Traps and system calls from user mode will start at its top.
+/// All other registers are cleared.
+pub unsafe fn enter_user(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let frame = TrapFrame {
+        rip: entry.0,
+        cs: SEL_UCODE as usize,
+        rflags: RFLAGS_IF | RFLAGS_RESERVED,
+        rsp: stack.0,
+        ss: SEL_UDATA as usize,
+        ..mem::zeroed()
+    };
+
+    asm!(
+        "
+            cli
+            mov     rsp, {}
+            jmp     x86_64_trap_return
+        ",
+        in(reg) addr_of!(frame),
+        options(noreturn),
+    );
+}
+
+#[no_mangle]
+unsafe extern "C" fn x86_64_trap_handler(frame: *mut TrapFrame) {
+    let frame = &mut *frame;
+
+    if frame.vector >= NUM_EXCEPTIONS {
+        interrupt(frame);
+        return;
+    }
+
+    // Stop recording branches before the path through the handler overwrites them. Faults
+    // in user code are not crashes, the recording carries on through them.
+    let kernel = frame.cs & 3 == 0;
+    if kernel {
+        lbr::freeze();
+    }
+
+    handle_exception(frame);
+
+    if kernel {
+        lbr::resume();
+    }
+}
+
+fn handle_exception(frame: &mut TrapFrame) {
+    if frame.vector == VEC_DF {
+        report_double_fault(frame);
+    }
+
+    if frame.vector == VEC_NMI && pmu::handle_nmi(frame) {
+        return;
+    }
+
+    if frame.vector == VEC_MC && mce::machine_check(frame) {
+        return;
+    }
+
+    // Faults expected by the kernel resume at their fixup.
+    if frame.cs & 3 == 0 && extable::fixup(frame) {
+        return;
+    }
+
+    let exception = match frame.vector {
+        VEC_DE | VEC_MF | VEC_XM => Exception::Arithmetic,
+        VEC_DB => Exception::Debug,
+        VEC_BP => Exception::Breakpoint,
+        VEC_UD => Exception::IllegalInstruction,
+        VEC_TS | VEC_NP | VEC_SS | VEC_GP => Exception::ProtectionFault,
+        VEC_PF => Exception::PageFault(PageFault {
+            addr:    VirtAddr(read_cr2()),
+            access:  if frame.error & 1 << 4 != 0 {
+                Access::Execute
+            } else if frame.error & 1 << 1 != 0 {
+                Access::Write
+            } else {
+                Access::Read
+            },
+            user:    frame.error & 1 << 2 != 0,
+            present: frame.error & 1 << 0 != 0,
+        }),
+        VEC_AC => Exception::Alignment,
+        VEC_MC => Exception::MachineCheck,
+        _ => Exception::Other,
+    };
+
+    trap::exception(frame, exception);
+}
+
+/*
+ * Interrupts
+ */
+
+/// Handler for an interrupt vector
+///
+/// Handlers are called with interrupts disabled. The end-of-interrupt is signalled after
+/// the handler returns.
+pub type InterruptHandler = fn(&mut TrapFrame);
+
+/// Vectors available for dynamic allocation by [`alloc_vector()`]
+///
+/// Vectors above this range are reserved for the Local APIC and IPIs.
+const DYNAMIC_VECTORS: Range<usize> = 0x30..0xf0;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Interrupt handlers, indexed by vector
+///
+/// Each entry holds an [`InterruptHandler`] or zero if the vector is unused.
+static INTERRUPT_HANDLERS: [AtomicUsize; 256] = [NO_HANDLER; 256];
+
+/// Install the handler for a fixed vector
+///
+/// # Panics
+///
+/// This function will panic if `vector` is an exception vector.
+pub fn register_handler(vector: u8, handler: InterruptHandler) {
+    assert!(vector as usize >= NUM_EXCEPTIONS);
+    INTERRUPT_HANDLERS[vector as usize].store(handler as usize, Ordering::Release);
+}
+
+/// Allocate a free vector and install `handler` for it
+///
+/// Returns `None` if there are no free vectors.
+pub fn alloc_vector(handler: InterruptHandler) -> Option<u8> {
+    DYNAMIC_VECTORS.into_iter().find_map(|vector| {
+        INTERRUPT_HANDLERS[vector]
+            .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .ok()
+            .map(|_| vector as u8)
+    })
+}
+
+/// Release a vector previously returned by [`alloc_vector()`]
+pub fn free_vector(vector: u8) {
+    debug_assert!(DYNAMIC_VECTORS.contains(&(vector as usize)));
+    INTERRUPT_HANDLERS[vector as usize].store(0, Ordering::Release);
+}
+
+fn interrupt(frame: &mut TrapFrame) {
+    let vector = frame.vector;
+
+    match INTERRUPT_HANDLERS[vector].load(Ordering::Acquire) {
+        0 => log::warn!("unhandled interrupt on vector {vector:#x}"),
+        handler => {
+            let handler = unsafe { mem::transmute::<usize, InterruptHandler>(handler) };
+            handler(frame);
+        }
+    }
+
+    // Spurious interrupts are not actually in service and must not be acknowledged.
+    if vector != lapic::VECTOR_SPURIOUS as usize {
+        lapic::eoi();
+    }
+}
+
+/// Try to explain a double fault
+///
+/// A double fault in the kernel is almost always a stack overflow: the processor could not
+/// push the frame for a page fault because the stack pointer ran into a guard page.
+fn report_double_fault(frame: &TrapFrame) {
+    let cr2 = VirtAddr(read_cr2());
+    let rsp = VirtAddr(frame.rsp);
+
+    match cpu::find_overflowed_stack(cr2).or_else(|| cpu::find_overflowed_stack(rsp)) {
+        Some(stack) => log::error!(
+            "!!! {stack} stack overflow: guard page hit at {cr2:p} (rsp = {rsp:p}) !!!"
+        ),
+        None => log::error!("!!! double fault: cr2 = {cr2:p}, rsp = {rsp:p} !!!"),
+    }
 }
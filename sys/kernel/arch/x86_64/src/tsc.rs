@@ -0,0 +1,204 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Time Stamp Counter
+//!
+//! The TSC is the cheapest clock available, but its frequency must be discovered. It is
+//! read from CPUID leaf 0x15 when the processor reports the crystal frequency, and
+//! otherwise measured against a reference timer.
+//!
+//! Only an invariant TSC, one which ticks at a constant rate regardless of power state,
+//! is preferred over other clock sources.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count, _rdtsc},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use super::pit;
+use crate::{
+    time::{self, ClockSource},
+    trap,
+};
+
+struct Tsc {
+    frequency: AtomicU64,
+    invariant: AtomicBool,
+}
+
+static TSC: Tsc = Tsc {
+    frequency: AtomicU64::new(0),
+    invariant: AtomicBool::new(false),
+};
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency.load(Ordering::Relaxed)
+    }
+
+    fn rating(&self) -> u32 {
+        match self.invariant.load(Ordering::Relaxed) {
+            true => 300,
+            false => 50,
+        }
+    }
+}
+
+/// Read the TSC
+///
+/// The read is ordered after all previous loads.
+#[inline]
+pub fn read() -> u64 {
+    unsafe {
+        asm!("lfence", options(nomem, nostack, preserves_flags));
+        _rdtsc()
+    }
+}
+
+/// Returns the frequency of the TSC in Hz, or zero if it is unknown
+pub fn frequency() -> u64 {
+    TSC.frequency.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the TSC runs at a constant rate in all power states
+pub fn is_invariant() -> bool {
+    TSC.invariant.load(Ordering::Relaxed)
+}
+
+/// Determine the TSC frequency and register it as a clock source
+pub fn init() {
+    let invariant = unsafe {
+        __cpuid(0x80000000).eax >= 0x80000007 && __cpuid(0x80000007).edx & (1 << 8) != 0
+    };
+    TSC.invariant.store(invariant, Ordering::Relaxed);
+    if !invariant {
+        log::warn!("tsc: not invariant, it may drift with power state changes");
+    }
+
+    let frequency = match cpuid_frequency() {
+        Some(frequency) => {
+            log::info!("tsc: {} kHz (cpuid)", frequency / 1000);
+            frequency
+        }
+        None => {
+            let frequency = match time::clocksource() {
+                Some(reference) => calibrate_against(reference),
+                None => calibrate_pit(),
+            };
+            log::info!("tsc: {} kHz (calibrated)", frequency / 1000);
+            if let Some(nominal) = nominal_frequency() {
+                log::info!("tsc: nominal frequency is {} kHz", nominal / 1000);
+            }
+            frequency
+        }
+    };
+    TSC.frequency.store(frequency, Ordering::Relaxed);
+
+    time::register_clocksource(&TSC);
+}
+
+/// Returns the TSC frequency as enumerated by CPUID leaf 0x15
+fn cpuid_frequency() -> Option<u64> {
+    if unsafe { __cpuid(0).eax } < 0x15 {
+        return None;
+    }
+
+    // EBX/EAX is the ratio of the TSC to the core crystal clock, ECX is the crystal frequency.
+    let leaf = unsafe { __cpuid_count(0x15, 0) };
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+
+    Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
+
+/// Returns the processor base frequency from CPUID leaf 0x16
+///
+/// This is only approximately the TSC frequency and is reported for comparison.
+fn nominal_frequency() -> Option<u64> {
+    if unsafe { __cpuid(0).eax } < 0x16 {
+        return None;
+    }
+
+    match unsafe { __cpuid_count(0x16, 0).eax } & 0xffff {
+        0 => None,
+        mhz => Some(mhz as u64 * 1_000_000),
+    }
+}
+
+const CALIBRATION_US: u64 = 10_000;
+const CALIBRATION_RUNS: usize = 5;
+
+/// Measure the TSC over a fixed PIT delay
+///
+/// Anything which interrupts the measurement can only make it longer, so the shortest of
+/// several runs is used.
+fn calibrate_pit() -> u64 {
+    let token = trap::disable();
+    let elapsed = (0..CALIBRATION_RUNS)
+        .map(|_| {
+            let start = read();
+            pit::delay_us(CALIBRATION_US);
+            read() - start
+        })
+        .min()
+        .unwrap();
+    trap::enable(token);
+
+    elapsed * (1_000_000 / CALIBRATION_US)
+}
+
+/// Measure the TSC against another clock source
+pub fn calibrate_against(reference: &dyn ClockSource) -> u64 {
+    let mask = reference.mask();
+    let ref_ticks = reference.frequency() * CALIBRATION_US / 1_000_000;
+
+    let token = trap::disable();
+    let ref_start = reference.read();
+    let tsc_start = read();
+    let (ref_elapsed, tsc_elapsed) = loop {
+        let ref_elapsed = reference.read().wrapping_sub(ref_start) & mask;
+        if ref_elapsed >= ref_ticks {
+            break (ref_elapsed, read() - tsc_start);
+        }
+        core::hint::spin_loop();
+    };
+    trap::enable(token);
+
+    (tsc_elapsed as u128 * reference.frequency() as u128 / ref_elapsed as u128) as u64
+}
@@ -34,10 +34,14 @@ use core::{
     fmt,
     mem::MaybeUninit,
     ptr::{self, NonNull},
+    time::Duration,
 };
 
-use super::ACPI_ROOT;
-use crate::vm::{PhysAddr, VirtAddr};
+use super::{pmtimer, ACPI_ROOT};
+use crate::{
+    time,
+    vm::{PhysAddr, VirtAddr},
+};
 
 #[allow(non_camel_case_types, non_upper_case_globals, non_snake_case)]
 pub mod lai_sys {
@@ -431,16 +435,21 @@ mod port_io {
 
 #[no_mangle]
 unsafe extern fn laihost_sleep(ms: u64) {
-    for _ in 0..1000 * ms {
-        core::hint::spin_loop();
+    let duration = Duration::from_millis(ms);
+    match pmtimer::get() {
+        Some(timer) => timer.delay(duration),
+        None => time::delay(duration),
     }
+}
 
-    // todo!("laihost_sleep({ms})");
+/// Returns a monotonic timestamp in units of 100 nanoseconds
+///
+/// The PM timer is registered as a clock source before the namespace is created, so the
+/// clock is always running by the time this is called.
+#[no_mangle]
+unsafe extern fn laihost_timer() -> u64 {
+    time::nanotime() / 100
 }
-// #[no_mangle]
-// unsafe extern fn laihost_timer() -> u64 {
-//     todo!()
-// }
 
 #[no_mangle]
 unsafe extern fn laihost_handle_amldebug(_var: *mut lai_sys::lai_variable_t) {
@@ -0,0 +1,342 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Multiple APIC Description Table
+
+use super::{ParseResult, Parser, ACPI_ROOT};
+use crate::{
+    intr::{Polarity, Trigger},
+    vm::{PhysAddr, VirtAddr},
+};
+
+const HEADER_SIZE: usize = 44;
+
+/// The system also has a PC-AT-compatible dual-8259 setup
+pub const PCAT_COMPAT: u32 = 1 << 0;
+
+/// The processor is ready for use
+pub const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+/// The processor may be enabled at runtime
+pub const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Clone, Copy, Debug)]
+pub enum MadtEntry {
+    /// A processor's Local APIC or Local x2APIC
+    LocalApic {
+        processor_uid: u32,
+        apic_id:       u32,
+        flags:         u32,
+    },
+    IoApic {
+        id:       u8,
+        address:  PhysAddr,
+        gsi_base: u32,
+    },
+    InterruptOverride {
+        bus:    u8,
+        source: u8,
+        gsi:    u32,
+        flags:  u16,
+    },
+    /// A Local APIC LINT pin connected to NMI
+    ///
+    /// A `processor_uid` of `!0` applies to all processors.
+    LocalApicNmi {
+        processor_uid: u32,
+        flags:         u16,
+        lint:          u8,
+    },
+    LocalApicAddressOverride {
+        address: PhysAddr,
+    },
+    Other(u8),
+}
+
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub flags:              u32,
+    entries:                &'static [u8],
+}
+
+impl Madt {
+    /// Returns the system's MADT, if there is one
+    pub fn get() -> Option<Madt> {
+        let root = ACPI_ROOT.read();
+        let table = VirtAddr(root.get_table_by_signature(*b"APIC", 0)?.addr());
+
+        unsafe {
+            let header = table.as_ptr::<u8>();
+            let length = header.add(4).cast::<u32>().read_unaligned() as usize;
+            let local_apic_address = header.add(36).cast::<u32>().read_unaligned();
+            let flags = header.add(40).cast::<u32>().read_unaligned();
+
+            Some(Madt {
+                local_apic_address: PhysAddr(local_apic_address as usize),
+                flags,
+                entries: core::slice::from_raw_parts(
+                    header.add(HEADER_SIZE),
+                    length.saturating_sub(HEADER_SIZE),
+                ),
+            })
+        }
+    }
+
+    pub fn entries(&self) -> MadtIter {
+        MadtIter {
+            parser: Parser::new(self.entries),
+        }
+    }
+}
+
+pub struct MadtIter {
+    parser: Parser<'static>,
+}
+
+impl Iterator for MadtIter {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let p = &mut self.parser;
+        let kind = p.bump().ok()?;
+        let len = p.bump().ok()? as usize;
+        let data = p.parse_slice(len.checked_sub(2)?).ok()?;
+        parse_entry(kind, &mut Parser::new(data)).ok()
+    }
+}
+
+fn parse_entry(kind: u8, p: &mut Parser) -> ParseResult<MadtEntry> {
+    let entry = match kind {
+        0 => MadtEntry::LocalApic {
+            processor_uid: p.bump()? as u32,
+            apic_id:       p.bump()? as u32,
+            flags:         p.parse_le_u32()?,
+        },
+        1 => {
+            let id = p.bump()?;
+            let _reserved = p.bump()?;
+            MadtEntry::IoApic {
+                id,
+                address: PhysAddr(p.parse_le_u32()? as usize),
+                gsi_base: p.parse_le_u32()?,
+            }
+        }
+        2 => MadtEntry::InterruptOverride {
+            bus:    p.bump()?,
+            source: p.bump()?,
+            gsi:    p.parse_le_u32()?,
+            flags:  p.parse_le_u16()?,
+        },
+        4 => MadtEntry::LocalApicNmi {
+            processor_uid: match p.bump()? {
+                0xff => !0,
+                uid => uid as u32,
+            },
+            flags:         p.parse_le_u16()?,
+            lint:          p.bump()?,
+        },
+        5 => {
+            let _reserved = p.parse_le_u16()?;
+            MadtEntry::LocalApicAddressOverride {
+                address: PhysAddr(p.parse_le_u64()? as usize),
+            }
+        }
+        9 => {
+            let _reserved = p.parse_le_u16()?;
+            let apic_id = p.parse_le_u32()?;
+            let flags = p.parse_le_u32()?;
+            MadtEntry::LocalApic {
+                processor_uid: p.parse_le_u32()?,
+                apic_id,
+                flags,
+            }
+        }
+        0xa => MadtEntry::LocalApicNmi {
+            flags:         p.parse_le_u16()?,
+            processor_uid: p.parse_le_u32()?,
+            lint:          p.bump()?,
+        },
+        _ => MadtEntry::Other(kind),
+    };
+
+    Ok(entry)
+}
+
+/// Decode the polarity of an MPS INTI flags field
+///
+/// Returns `None` if the interrupt conforms to the specifications of the bus.
+pub fn inti_polarity(flags: u16) -> Option<Polarity> {
+    match flags & 0x3 {
+        0b01 => Some(Polarity::High),
+        0b11 => Some(Polarity::Low),
+        _ => None,
+    }
+}
+
+/// Decode the trigger mode of an MPS INTI flags field
+///
+/// Returns `None` if the interrupt conforms to the specifications of the bus.
+pub fn inti_trigger(flags: u16) -> Option<Trigger> {
+    match flags >> 2 & 0x3 {
+        0b01 => Some(Trigger::Edge),
+        0b11 => Some(Trigger::Level),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::test_cases!(madt_entries, madt_truncated, inti_flags);
+
+    /// A processor, an I/O APIC, an override, NMIs and an address override, each in the
+    /// layout given by the ACPI specification
+    #[rustfmt::skip]
+    static ENTRIES: [u8; 76] = [
+        // Processor Local APIC
+        0, 8, 3, 5, 1, 0, 0, 0,
+        // I/O APIC
+        1, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 24, 0, 0, 0,
+        // Interrupt Source Override
+        2, 10, 0, 9, 20, 0, 0, 0, 0x0d, 0,
+        // Local APIC NMI
+        4, 6, 0xff, 0x05, 0, 1,
+        // Local APIC Address Override
+        5, 12, 0, 0, 0x00, 0x00, 0xe0, 0xfe, 0, 0, 0, 0,
+        // Processor Local x2APIC
+        9, 16, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0,
+        // Local x2APIC NMI
+        0xa, 12, 0x05, 0, 0xff, 0xff, 0xff, 0xff, 1, 0, 0, 0,
+    ];
+
+    fn madt_entries() {
+        let madt = Madt {
+            local_apic_address: PhysAddr(0xfee00000),
+            flags:              PCAT_COMPAT,
+            entries:            &ENTRIES,
+        };
+        let mut entries = madt.entries();
+
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::LocalApic {
+                processor_uid: 3,
+                apic_id:       5,
+                flags:         LOCAL_APIC_ENABLED,
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::IoApic {
+                id:       2,
+                address:  PhysAddr(0xfec00000),
+                gsi_base: 24,
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::InterruptOverride {
+                bus:    0,
+                source: 9,
+                gsi:    20,
+                flags:  0x0d,
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::LocalApicNmi {
+                processor_uid: 0xffffffff,
+                flags:         0x05,
+                lint:          1,
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::LocalApicAddressOverride {
+                address: PhysAddr(0xfee00000),
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::LocalApic {
+                processor_uid: 7,
+                apic_id:       0x100,
+                flags:         LOCAL_APIC_ENABLED,
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::LocalApicNmi {
+                processor_uid: 0xffffffff,
+                flags:         0x05,
+                lint:          1,
+            })
+        ));
+        assert!(entries.next().is_none());
+    }
+
+    /// Entries which are too short, or extend past the end of the table, end iteration
+    fn madt_truncated() {
+        static SHORT: [u8; 6] = [0, 6, 3, 5, 1, 0];
+        static PAST_END: [u8; 10] = [0, 8, 3, 5, 1, 0, 0, 0, 1, 12];
+        static UNKNOWN: [u8; 4] = [0x7f, 4, 0, 0];
+
+        assert!(parse_entry(0, &mut Parser::new(&SHORT[2..])).is_err());
+
+        let mut entries = MadtIter {
+            parser: Parser::new(&SHORT),
+        };
+        assert!(entries.next().is_none());
+
+        let mut entries = MadtIter {
+            parser: Parser::new(&PAST_END),
+        };
+        assert!(matches!(entries.next(), Some(MadtEntry::LocalApic { .. })));
+        assert!(entries.next().is_none());
+
+        let mut entries = MadtIter {
+            parser: Parser::new(&UNKNOWN),
+        };
+        assert!(matches!(entries.next(), Some(MadtEntry::Other(0x7f))));
+        assert!(entries.next().is_none());
+    }
+
+    fn inti_flags() {
+        assert_eq!(inti_polarity(0b00), None);
+        assert_eq!(inti_polarity(0b01), Some(Polarity::High));
+        assert_eq!(inti_polarity(0b10), None);
+        assert_eq!(inti_polarity(0b11), Some(Polarity::Low));
+
+        assert_eq!(inti_trigger(0b00 << 2), None);
+        assert_eq!(inti_trigger(0b01 << 2), Some(Trigger::Edge));
+        assert_eq!(inti_trigger(0b10 << 2), None);
+        assert_eq!(inti_trigger(0b11 << 2 | 0b11), Some(Trigger::Level));
+    }
+}
@@ -29,6 +29,8 @@
  */
 
 mod lai;
+pub mod madt;
+pub mod pmtimer;
 
 use alloc::sync::Arc;
 
@@ -41,8 +43,8 @@ use crate::{
         resource::{IoResource, IrqFlags, IrqResource},
         Device,
     },
-    drivers::pcie,
-    intr::{Polarity, Trigger},
+    drivers::{self, pcie},
+    intr::{self, Polarity, Trigger},
     sync::{lazy::Lazy, RwLock},
     vm::{PhysAddr, VirtAddr},
 };
@@ -125,7 +127,7 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_le_u64(&mut self) -> ParseResult<u64> {
-        let data = self.parse_slice(4)?;
+        let data = self.parse_slice(8)?;
         Ok(unsafe { data.as_ptr().cast::<u64>().read_unaligned() })
     }
 }
@@ -151,20 +153,29 @@ fn parse_inner(p: &mut Parser, resources: &mut Vec<dev::Resource>) -> ParseResul
             // IRQ
             0x4 => {
                 let mask = p.parse_le_u16()?;
-                for irq in (0..16).filter(|bit| mask & 1 << bit != 0) {
-                    let mut trigger = Trigger::Edge;
-                    let mut polarity = Polarity::High;
+                for isa_irq in (0..16).filter(|bit| mask & 1 << bit != 0) {
+                    // These are ISA IRQ numbers, which may be redirected by an interrupt source
+                    // override. The override also supplies the trigger mode and polarity,
+                    // unless the descriptor specifies them itself.
+                    let route = intr::isa_route(isa_irq);
+                    let irq = route.irq;
+                    let mut trigger = route.trigger;
+                    let mut polarity = route.polarity;
                     let mut flags = IrqFlags::empty();
 
                     if len == 3 {
                         let info = p.bump()?;
 
-                        if info & 1 << 0 == 0 {
-                            trigger = Trigger::Level;
-                        }
-                        if info & 1 << 3 != 0 {
-                            polarity = Polarity::Low;
-                        }
+                        trigger = if info & 1 << 0 == 0 {
+                            Trigger::Level
+                        } else {
+                            Trigger::Edge
+                        };
+                        polarity = if info & 1 << 3 != 0 {
+                            Polarity::Low
+                        } else {
+                            Polarity::High
+                        };
                         flags.set(IrqFlags::SHARED, info & 1 << 4 != 0);
                         flags.set(IrqFlags::WAKE_CAPABLE, info & 1 << 5 != 0);
                     }
@@ -345,10 +356,9 @@ fn probe_node(parent: &Arc<Device>, node: &mut lai::NsNode) {
 
     println!("ACPI: {id:?}");
 
-    #[cfg(notyet)]
     #[allow(clippy::single_match)]
     match id.as_str() {
-        "PNP0103" => dev::hpet::attach(&dev),
+        "PNP0103" => drivers::hpet::attach(&dev),
         _ => {}
     }
 }
@@ -387,6 +397,13 @@ pub unsafe fn init(dev: &Arc<Device>, rsdp_ptr: *const u8) {
 
     Lazy::initialize_with(&ACPI_ROOT, RwLock::new(root));
 
+    // The interrupt controllers must be known before any resources are parsed, since ISA
+    // IRQ numbers are translated through the MADT's interrupt source overrides.
+    #[cfg(target_arch = "x86_64")]
+    crate::arch::ioapic::init();
+
+    pmtimer::init();
+
     lai::lai_sys::lai_create_namespace();
     lai::lai_sys::lai_enable_acpi(1);
 
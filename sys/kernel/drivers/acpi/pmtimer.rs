@@ -0,0 +1,187 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! ACPI Power Management Timer
+//!
+//! The PM timer is a 24- or 32-bit counter running at 3.579545 MHz, described by the FADT.
+//! It is present on every system with full (not hardware-reduced) ACPI, and is what the
+//! AML interpreter uses to measure time.
+
+use core::{ptr, time::Duration};
+
+use super::ACPI_ROOT;
+use crate::{
+    sync::lazy::Lazy,
+    time::{self, ClockSource},
+    vm::{PhysAddr, VirtAddr},
+};
+
+/// Frequency of the PM timer, in Hz
+pub const FREQUENCY: u64 = 3_579_545;
+
+// FADT field offsets
+const FADT_PM_TMR_BLK: usize = 76;
+const FADT_PM_TMR_LEN: usize = 91;
+const FADT_FLAGS: usize = 112;
+const FADT_X_PM_TMR_BLK: usize = 208;
+const GAS_SIZE: usize = 12;
+
+/// The timer is 32 bits wide rather than 24
+const FADT_FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8 = 1;
+
+enum Register {
+    Io(u16),
+    Memory(*const u32),
+}
+
+pub struct PmTimer {
+    register: Register,
+    mask:     u64,
+}
+
+unsafe impl Send for PmTimer {}
+unsafe impl Sync for PmTimer {}
+
+static PM_TIMER: Lazy<Option<PmTimer>> = Lazy::new(PmTimer::from_fadt);
+
+impl PmTimer {
+    fn from_fadt() -> Option<PmTimer> {
+        let root = ACPI_ROOT.read();
+        let fadt = VirtAddr(root.get_table_by_signature(*b"FACP", 0)?.addr()).as_ptr::<u8>();
+
+        let (register, extended) = unsafe {
+            let length = fadt.add(4).cast::<u32>().read_unaligned() as usize;
+            let flags = fadt.add(FADT_FLAGS).cast::<u32>().read_unaligned();
+
+            let x_pm_tmr_blk = match length >= FADT_X_PM_TMR_BLK + GAS_SIZE {
+                true => {
+                    let space = fadt.add(FADT_X_PM_TMR_BLK).read();
+                    let address = fadt.add(FADT_X_PM_TMR_BLK + 4).cast::<u64>().read_unaligned();
+                    Some((space, address)).filter(|&(_, address)| address != 0)
+                }
+                false => None,
+            };
+            let pm_tmr_blk = fadt.add(FADT_PM_TMR_BLK).cast::<u32>().read_unaligned();
+            let pm_tmr_len = fadt.add(FADT_PM_TMR_LEN).read();
+
+            let register = match x_pm_tmr_blk {
+                Some((GAS_SYSTEM_IO, port)) => Register::Io(port as u16),
+                Some((GAS_SYSTEM_MEMORY, address)) => {
+                    Register::Memory(PhysAddr(address as usize).to_virt().as_ptr())
+                }
+                Some((space, _)) => {
+                    log::warn!("acpi: pm timer in unsupported address space {space}");
+                    return None;
+                }
+                None if pm_tmr_blk != 0 && pm_tmr_len == 4 => Register::Io(pm_tmr_blk as u16),
+                None => return None,
+            };
+
+            (register, flags & FADT_FLAG_TMR_VAL_EXT != 0)
+        };
+
+        if let Register::Io(_) = register {
+            if cfg!(not(target_arch = "x86_64")) {
+                return None;
+            }
+        }
+
+        let timer = PmTimer {
+            register,
+            mask: if extended { u32::MAX as u64 } else { 0xff_ffff },
+        };
+
+        log::info!("acpi: pm timer is {}-bit", if extended { 32 } else { 24 });
+
+        Some(timer)
+    }
+
+    /// Busy-wait for at least `duration`
+    ///
+    /// This is correct for any duration, the counter is sampled often enough to see every
+    /// wrap.
+    pub fn delay(&self, duration: Duration) {
+        let ticks = (duration.as_nanos() * FREQUENCY as u128 / 1_000_000_000) as u64;
+
+        let mut last = self.read();
+        let mut elapsed = 0;
+        while elapsed < ticks {
+            core::hint::spin_loop();
+            let now = self.read();
+            elapsed += now.wrapping_sub(last) & self.mask;
+            last = now;
+        }
+    }
+}
+
+impl ClockSource for PmTimer {
+    fn name(&self) -> &str {
+        "acpi_pm"
+    }
+
+    fn read(&self) -> u64 {
+        let value = match self.register {
+            #[cfg(target_arch = "x86_64")]
+            Register::Io(port) => unsafe { crate::arch::pio::inl(port) },
+            #[cfg(not(target_arch = "x86_64"))]
+            Register::Io(_) => unreachable!(),
+            Register::Memory(address) => unsafe { ptr::read_volatile(address) },
+        };
+        value as u64 & self.mask
+    }
+
+    fn frequency(&self) -> u64 {
+        FREQUENCY
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+}
+
+/// Returns the PM timer, if the system has one
+pub fn get() -> Option<&'static PmTimer> {
+    PM_TIMER.as_ref()
+}
+
+/// Register the PM timer as a clock source
+pub fn init() {
+    match get() {
+        Some(timer) => time::register_clocksource(timer),
+        None => log::info!("acpi: no pm timer"),
+    }
+}
@@ -0,0 +1,352 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! High Precision Event Timer
+//!
+//! The HPET is a single free-running counter with a number of comparators, each of which
+//! can raise an interrupt when the counter reaches its value. The counter is registered
+//! as a clock source and the first comparator as an event source.
+//!
+//! If the HPET is capable of legacy replacement routing, comparator 0 takes over ISA IRQ 0
+//! from the PIT. Otherwise it is routed to an interrupt from its capability mask.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::{
+    dev::{self, Device},
+    intr::{self, Polarity, Trigger},
+    time::{self, ClockSource, EventHandler, EventSource, NANOS_PER_SEC},
+    vm::PhysAddr,
+};
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_INTERRUPT_STATUS: usize = 0x020;
+const REG_COUNTER: usize = 0x0f0;
+
+const fn reg_timer_config(n: usize) -> usize {
+    0x100 + 0x20 * n
+}
+
+const fn reg_timer_comparator(n: usize) -> usize {
+    0x108 + 0x20 * n
+}
+
+const CAP_NUM_TIMERS_SHIFT: u64 = 8;
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+const CAP_PERIOD_SHIFT: u64 = 32;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_64BIT_CAP: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_ROUTE_CAP_SHIFT: u64 = 32;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// Don't program an event closer than this many ticks, it may be missed entirely
+const MIN_DELTA: u64 = 16;
+
+pub struct Hpet {
+    base:            *mut u64,
+    frequency:       u64,
+    counter_64bit:   bool,
+    num_comparators: usize,
+}
+
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+impl Hpet {
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { self.base.add(reg / 8).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u64) {
+        unsafe { self.base.add(reg / 8).write_volatile(value) };
+    }
+
+    fn counter(&self) -> u64 {
+        match self.counter_64bit {
+            true => self.read(REG_COUNTER),
+            false => self.read(REG_COUNTER) & u32::MAX as u64,
+        }
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * self.frequency as u128 / NANOS_PER_SEC as u128) as u64
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        self.counter()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        match self.counter_64bit {
+            true => u64::MAX,
+            false => u32::MAX as u64,
+        }
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+}
+
+/// A comparator used as an event source
+pub struct Comparator {
+    hpet:     &'static Hpet,
+    index:    usize,
+    irq:      u32,
+    trigger:  Trigger,
+    periodic: bool,
+    handler:  AtomicUsize,
+}
+
+impl Comparator {
+    fn config(&self) -> u64 {
+        self.hpet.read(reg_timer_config(self.index))
+    }
+
+    fn set_config(&self, config: u64) {
+        self.hpet.write(reg_timer_config(self.index), config);
+    }
+}
+
+impl EventSource for Comparator {
+    fn name(&self) -> &str {
+        "hpet"
+    }
+
+    fn set_handler(&self, handler: EventHandler) {
+        self.handler.store(handler as usize, Ordering::Release);
+    }
+
+    fn oneshot(&self, ns: u64) {
+        let hpet = self.hpet;
+        let mask = hpet.mask();
+        let mut delta = hpet.ns_to_ticks(ns).max(MIN_DELTA);
+
+        self.set_config(self.config() & !TIMER_PERIODIC | TIMER_ENABLE);
+
+        // The comparator only matches on equality, so if the counter passes it before the
+        // write lands the event is lost until the counter wraps. Check, and try again
+        // further out.
+        loop {
+            let target = hpet.counter().wrapping_add(delta) & mask;
+            hpet.write(reg_timer_comparator(self.index), target);
+
+            let remaining = target.wrapping_sub(hpet.counter()) & mask;
+            if remaining != 0 && remaining <= delta {
+                break;
+            }
+            delta *= 2;
+        }
+    }
+
+    fn supports_periodic(&self) -> bool {
+        self.periodic
+    }
+
+    fn periodic(&self, ns: u64) {
+        assert!(self.periodic, "hpet comparator {} is not periodic-capable", self.index);
+
+        let hpet = self.hpet;
+        let period = hpet.ns_to_ticks(ns).max(MIN_DELTA);
+
+        // With `TIMER_VALUE_SET`, the first write sets the time of the first event and the
+        // second sets the period added to it each time the comparator fires.
+        self.set_config(self.config() | TIMER_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET);
+        let first = hpet.counter().wrapping_add(period) & hpet.mask();
+        hpet.write(reg_timer_comparator(self.index), first);
+        hpet.write(reg_timer_comparator(self.index), period);
+    }
+
+    fn stop(&self) {
+        self.set_config(self.config() & !(TIMER_ENABLE | TIMER_PERIODIC));
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+}
+
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+/// The comparator driving the event source, null until its interrupt is set up
+static EVENT_COMPARATOR: AtomicPtr<Comparator> = AtomicPtr::new(ptr::null_mut());
+
+fn interrupt(_irq: u32) {
+    // The interrupt line may be shared with devices which fire first.
+    let Some(comparator) = (unsafe { EVENT_COMPARATOR.load(Ordering::Acquire).as_ref() }) else {
+        return;
+    };
+
+    // Level-triggered interrupts stay asserted until the status bit is cleared.
+    if comparator.trigger == Trigger::Level {
+        comparator
+            .hpet
+            .write(REG_INTERRUPT_STATUS, 1 << comparator.index);
+    }
+
+    let handler = comparator.handler.load(Ordering::Acquire);
+    if handler != 0 {
+        let handler = unsafe { core::mem::transmute::<usize, EventHandler>(handler) };
+        handler();
+    }
+}
+
+/// Attach to the HPET described by an ACPI `PNP0103` device
+pub fn attach(dev: &Device) {
+    if ATTACHED.swap(true, Ordering::AcqRel) {
+        log::warn!("hpet: ignoring additional HPET {}", dev.name());
+        return;
+    }
+
+    let Some(base) = dev.resources().iter().find_map(|res| match res {
+        dev::Resource::Memory(mem) => Some(mem.base),
+        _ => None,
+    }) else {
+        log::warn!("hpet: {} has no memory resource", dev.name());
+        return;
+    };
+
+    let base = PhysAddr(base).to_virt().as_mut_ptr::<u64>();
+    let capabilities = unsafe { base.add(REG_CAPABILITIES / 8).read_volatile() };
+
+    let period_fs = capabilities >> CAP_PERIOD_SHIFT;
+    if period_fs == 0 || period_fs > 100_000_000 {
+        log::warn!("hpet: invalid counter period {period_fs} fs");
+        return;
+    }
+
+    let hpet = Box::leak(Box::new(Hpet {
+        base,
+        frequency: FEMTOS_PER_SEC / period_fs,
+        counter_64bit: capabilities & CAP_COUNTER_64BIT != 0,
+        num_comparators: (capabilities >> CAP_NUM_TIMERS_SHIFT & 0x1f) as usize + 1,
+    }));
+    let legacy = capabilities & CAP_LEGACY_ROUTE != 0;
+
+    log::info!(
+        "hpet: {} kHz, {}-bit counter, {} comparators{}",
+        hpet.frequency / 1000,
+        if hpet.counter_64bit { 64 } else { 32 },
+        hpet.num_comparators,
+        if legacy { ", legacy replacement capable" } else { "" },
+    );
+
+    // Halt the counter and quiesce every comparator before touching the configuration.
+    let mut config = hpet.read(REG_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    hpet.write(REG_CONFIG, config);
+    for n in 0..hpet.num_comparators {
+        let timer = hpet.read(reg_timer_config(n));
+        log::debug!(
+            "hpet: comparator {n}: {}-bit{}, routes {:#010x}",
+            if timer & TIMER_64BIT_CAP != 0 { 64 } else { 32 },
+            if timer & TIMER_PERIODIC_CAP != 0 { ", periodic" } else { "" },
+            timer >> TIMER_ROUTE_CAP_SHIFT,
+        );
+        hpet.write(
+            reg_timer_config(n),
+            timer & !(TIMER_ENABLE | TIMER_PERIODIC | TIMER_FSB_ENABLE),
+        );
+    }
+    hpet.write(REG_INTERRUPT_STATUS, !0);
+
+    // Comparator 0 drives the event source.
+    let timer = hpet.read(reg_timer_config(0));
+    let (irq, trigger, polarity, route) = if legacy {
+        // Comparator 0 replaces the PIT on ISA IRQ 0, comparator 1 the RTC on IRQ 8.
+        config |= CONFIG_LEGACY_ROUTE;
+        let isa = intr::isa_route(0);
+        (isa.irq, Trigger::Edge, isa.polarity, 0)
+    } else {
+        // Prefer inputs above the ISA range, they are less likely to be shared.
+        let routes = timer >> TIMER_ROUTE_CAP_SHIFT;
+        if routes == 0 {
+            log::warn!("hpet: comparator 0 cannot be routed to an interrupt");
+            return;
+        }
+        let irq = 63 - routes.leading_zeros();
+        (irq, Trigger::Level, Polarity::Low, irq as u64)
+    };
+
+    let mut timer = timer & !(TIMER_ROUTE_MASK | TIMER_LEVEL | TIMER_32BIT_MODE);
+    timer |= route << TIMER_ROUTE_SHIFT;
+    if trigger == Trigger::Level {
+        timer |= TIMER_LEVEL;
+    }
+    if !hpet.counter_64bit || timer & TIMER_64BIT_CAP == 0 {
+        timer |= TIMER_32BIT_MODE;
+    }
+    hpet.write(reg_timer_config(0), timer);
+
+    let comparator = Box::leak(Box::new(Comparator {
+        hpet,
+        index: 0,
+        irq,
+        trigger,
+        periodic: timer & TIMER_PERIODIC_CAP != 0,
+        handler: AtomicUsize::new(0),
+    }));
+    EVENT_COMPARATOR.store(&mut *comparator, Ordering::Release);
+
+    hpet.write(REG_CONFIG, config | CONFIG_ENABLE);
+
+    if let Err(error) = intr::install(comparator.irq, trigger, polarity, interrupt) {
+        log::warn!("hpet: failed to install interrupt handler for irq {irq}: {error:?}");
+    } else {
+        time::register_eventsource(comparator);
+    }
+    time::register_clocksource(hpet);
+}
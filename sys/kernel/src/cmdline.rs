@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Kernel Command Line
+//!
+//! The command line is a list of whitespace-separated options, each either a bare flag or
+//! a `key=value` pair. When an option is given more than once, the last one wins.
+
+use crate::sync::lazy::Lazy;
+
+static CMDLINE: Lazy<&'static str> = Lazy::new(|| "");
+
+/// Set the command line passed by the bootloader
+///
+/// This must be called before anything reads an option.
+pub fn init(cmdline: &'static str) {
+    Lazy::initialize_with(&CMDLINE, cmdline);
+    log::info!("command line: {cmdline:?}");
+}
+
+/// Returns the whole command line
+pub fn cmdline() -> &'static str {
+    *CMDLINE
+}
+
+/// Returns the value of option `key`
+///
+/// Flags without a value are returned as an empty string.
+pub fn get(key: &str) -> Option<&'static str> {
+    find(*CMDLINE, key)
+}
+
+/// Returns `true` if flag `key` is present
+pub fn flag(key: &str) -> bool {
+    get(key).is_some()
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_ascii_whitespace()
+        .filter_map(|option| match option.split_once('=') {
+            Some((k, value)) => (k == key).then_some(value),
+            None => (option == key).then_some(""),
+        })
+        .last()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::test_cases!(find_options, find_last_wins);
+
+    fn find_options() {
+        let cmdline = "  quiet mitigations=off\tspectre_v2=  console=ttyS0,115200 ";
+
+        assert_eq!(find(cmdline, "quiet"), Some(""));
+        assert_eq!(find(cmdline, "mitigations"), Some("off"));
+        assert_eq!(find(cmdline, "spectre_v2"), Some(""));
+        assert_eq!(find(cmdline, "console"), Some("ttyS0,115200"));
+        assert_eq!(find(cmdline, "mitigation"), None);
+        assert_eq!(find(cmdline, "off"), None);
+        assert_eq!(find("", "quiet"), None);
+    }
+
+    /// Options given more than once, in both forms
+    fn find_last_wins() {
+        assert_eq!(find("a=1 a=2", "a"), Some("2"));
+        assert_eq!(find("a=1 a", "a"), Some(""));
+        assert_eq!(find("a a=1", "a"), Some("1"));
+        // Only the first `=` separates the key from the value.
+        assert_eq!(find("a=b=c", "a"), Some("b=c"));
+    }
+}
@@ -28,20 +28,35 @@
  * SPDX-License-Identifier: BSD-3-Clause
  */
 
+use core::{
+    ptr::addr_of,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
 use crate::arch;
 
 pub trait ArchCpu {
     type Data;
+    /// Description of a CPU which does not change once it has been registered
+    type Identity: Sync;
 
     fn get_current_cpu() -> *mut Cpu;
 }
 
 pub type MdCpu = <arch::ThisArch as ArchCpu>::Data;
+pub type MdCpuIdentity = <arch::ThisArch as ArchCpu>::Identity;
 
 /// Per-CPU Structure
 ///
 /// The `Cpu` struct for the current CPU can be retrieved with [`this_cpu()`].
 pub struct Cpu {
+    /// Logical CPU number
+    ///
+    /// CPUs are numbered consecutively from zero in the order they are brought up,
+    /// the boot CPU is always CPU 0.
+    pub cpu_id: usize,
+    /// Written before the CPU is registered and never modified afterwards
+    pub md_identity: MdCpuIdentity,
     pub md_data: MdCpu,
 }
 
@@ -55,3 +70,72 @@ pub struct Cpu {
 pub fn this_cpu() -> *mut Cpu {
     <arch::ThisArch as ArchCpu>::get_current_cpu()
 }
+
+/// Maximum number of CPUs supported
+pub const MAX_CPUS: usize = 256;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CPU: AtomicPtr<Cpu> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Every CPU which has been initialized, indexed by `cpu_id`
+static CPUS: [AtomicPtr<Cpu>; MAX_CPUS] = [NO_CPU; MAX_CPUS];
+
+/// Add a CPU to the registry
+///
+/// # Safety
+///
+/// `cpu` must be valid for the lifetime of the kernel, and its `cpu_id` and `md_identity`
+/// must be set.
+pub unsafe fn register(cpu: *mut Cpu) {
+    let cpu_id = (*cpu).cpu_id;
+    assert!(cpu_id < MAX_CPUS, "cpu{cpu_id} exceeds MAX_CPUS");
+    CPUS[cpu_id].store(cpu, Ordering::Release);
+}
+
+/// Remove a CPU from the registry
+///
+/// # Safety
+///
+/// The CPU must not be referenced by any other CPU, it may only be called by a CPU which
+/// never finished coming online.
+pub unsafe fn unregister(cpu_id: usize) {
+    CPUS[cpu_id].store(core::ptr::null_mut(), Ordering::Release);
+}
+
+/// A registered CPU, as seen by the rest of the system
+///
+/// Only the parts of a [`Cpu`] which never change after registration are exposed. Everything
+/// else is owned by the CPU itself, which keeps modifying it.
+#[derive(Clone, Copy)]
+pub struct CpuRef {
+    pub cpu_id:   usize,
+    pub identity: &'static MdCpuIdentity,
+}
+
+impl CpuRef {
+    fn load(slot: &AtomicPtr<Cpu>) -> Option<CpuRef> {
+        let cpu = slot.load(Ordering::Acquire);
+        if cpu.is_null() {
+            return None;
+        }
+
+        unsafe {
+            Some(CpuRef {
+                cpu_id:   addr_of!((*cpu).cpu_id).read(),
+                identity: &*addr_of!((*cpu).md_identity),
+            })
+        }
+    }
+}
+
+/// Returns CPU `cpu_id`, if it has been registered
+pub fn get(cpu_id: usize) -> Option<CpuRef> {
+    CpuRef::load(CPUS.get(cpu_id)?)
+}
+
+/// Returns an iterator over every registered CPU, in order of `cpu_id`
+///
+/// CPUs coming online concurrently may or may not be included.
+pub fn cpus() -> impl Iterator<Item = CpuRef> {
+    CPUS.iter().filter_map(CpuRef::load)
+}
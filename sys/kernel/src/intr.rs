@@ -0,0 +1,258 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Interrupt Management
+//!
+//! Device interrupts are identified by a global interrupt number (the GSI on ACPI systems).
+//! Each interrupt is owned by the [`IrqChip`] responsible for its range of numbers, which
+//! are registered by the architecture as controllers are discovered.
+
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::{
+    cpu::CpuRef,
+    dev::resource::IrqResource,
+    sync::{mutex::MutexKind, Mutex},
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No interrupt controller handles this interrupt
+    NoController(u32),
+    /// The interrupt already has a handler installed
+    Busy(u32),
+    /// The interrupt controller was unable to allocate a vector
+    NoVectors,
+    /// The interrupt controller is unable to deliver the interrupt to the requested CPU
+    Unreachable(u32),
+}
+
+/// An interrupt controller
+pub trait IrqChip: Sync {
+    fn name(&self) -> &str;
+
+    /// Returns `true` if this controller is responsible for `irq`
+    fn handles(&self, irq: u32) -> bool;
+
+    /// Route `irq` to the current CPU with the given trigger mode and polarity
+    ///
+    /// The interrupt is left masked.
+    fn setup(&self, irq: u32, trigger: Trigger, polarity: Polarity) -> Result<()>;
+
+    fn mask(&self, irq: u32);
+    fn unmask(&self, irq: u32);
+
+    /// Deliver `irq` to another CPU
+    ///
+    /// The routing is left unchanged if `cpu` cannot be reached.
+    fn retarget(&self, irq: u32, cpu: CpuRef) -> Result<()>;
+}
+
+/// Handler for a device interrupt
+///
+/// Handlers are called with interrupts disabled.
+pub type IrqHandler = fn(irq: u32);
+
+pub const MAX_IRQS: usize = 256;
+
+const MAX_CHIPS: usize = 16;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CHIP: AtomicPtr<&'static dyn IrqChip> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Registered interrupt controllers
+///
+/// Controllers are published once and never removed, so they can be looked up from interrupt
+/// context without taking a lock. Only the first [`NUM_CHIPS`] entries are valid.
+static CHIPS: [AtomicPtr<&'static dyn IrqChip>; MAX_CHIPS] = [NO_CHIP; MAX_CHIPS];
+static NUM_CHIPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes [`register_chip()`]
+static REGISTER_LOCK: Mutex<()> = Mutex::new(MutexKind::Spin, ());
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Installed handlers, indexed by interrupt number
+///
+/// Each entry holds an [`IrqHandler`] or zero if no handler is installed.
+static HANDLERS: [AtomicUsize; MAX_IRQS] = [NO_HANDLER; MAX_IRQS];
+
+pub fn register_chip(chip: &'static dyn IrqChip) {
+    let _guard = REGISTER_LOCK.lock();
+
+    let index = NUM_CHIPS.load(Ordering::Relaxed);
+    assert!(index < MAX_CHIPS, "too many interrupt controllers");
+    CHIPS[index].store(Box::leak(Box::new(chip)), Ordering::Relaxed);
+    NUM_CHIPS.store(index + 1, Ordering::Release);
+
+    log::info!("registered interrupt controller {}", chip.name());
+}
+
+fn find_chip(irq: u32) -> Result<&'static dyn IrqChip> {
+    CHIPS[..NUM_CHIPS.load(Ordering::Acquire)]
+        .iter()
+        .map(|slot| unsafe { *slot.load(Ordering::Relaxed) })
+        .find(|chip| chip.handles(irq))
+        .ok_or(Error::NoController(irq))
+}
+
+/// Install a handler for `irq` and unmask it
+///
+/// Shared interrupts are not supported.
+pub fn install(irq: u32, trigger: Trigger, polarity: Polarity, handler: IrqHandler) -> Result<()> {
+    let chip = find_chip(irq)?;
+    let slot = HANDLERS.get(irq as usize).ok_or(Error::NoController(irq))?;
+
+    slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+        .map_err(|_| Error::Busy(irq))?;
+
+    if let Err(error) = chip.setup(irq, trigger, polarity) {
+        slot.store(0, Ordering::Release);
+        return Err(error);
+    }
+    chip.unmask(irq);
+
+    Ok(())
+}
+
+/// Install a handler for an interrupt described by a device resource
+pub fn install_resource(resource: &IrqResource, handler: IrqHandler) -> Result<()> {
+    install(resource.irq, resource.trigger, resource.polarity, handler)
+}
+
+/// Mask `irq` and remove its handler
+pub fn uninstall(irq: u32) {
+    mask(irq);
+    if let Some(slot) = HANDLERS.get(irq as usize) {
+        slot.store(0, Ordering::Release);
+    }
+}
+
+pub fn mask(irq: u32) {
+    if let Ok(chip) = find_chip(irq) {
+        chip.mask(irq);
+    }
+}
+
+pub fn unmask(irq: u32) {
+    if let Ok(chip) = find_chip(irq) {
+        chip.unmask(irq);
+    }
+}
+
+pub fn retarget(irq: u32, cpu: CpuRef) -> Result<()> {
+    find_chip(irq)?.retarget(irq, cpu)
+}
+
+/// Dispatch a device interrupt
+///
+/// This is called by interrupt controller drivers from interrupt context.
+pub fn handle(irq: u32) {
+    let handler = HANDLERS
+        .get(irq as usize)
+        .map_or(0, |slot| slot.load(Ordering::Acquire));
+
+    if handler == 0 {
+        // Keep an unexpected level-triggered interrupt from firing forever.
+        log::warn!("spurious irq {irq}, masking");
+        mask(irq);
+        return;
+    }
+
+    let handler = unsafe { core::mem::transmute::<usize, IrqHandler>(handler) };
+    handler(irq);
+}
+
+/*
+ * Legacy ISA Interrupts
+ */
+
+/// Routing of a legacy ISA IRQ
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IsaRoute {
+    pub irq:      u32,
+    pub trigger:  Trigger,
+    pub polarity: Polarity,
+}
+
+impl IsaRoute {
+    /// ISA interrupts are identity-mapped, edge-triggered and active-high unless overridden.
+    const fn identity(isa_irq: u8) -> IsaRoute {
+        Self {
+            irq:      isa_irq as u32,
+            trigger:  Trigger::Edge,
+            polarity: Polarity::High,
+        }
+    }
+}
+
+static ISA_ROUTES: Mutex<[IsaRoute; 16]> = Mutex::new(MutexKind::Spin, {
+    let mut routes = [IsaRoute::identity(0); 16];
+    let mut i = 0;
+    while i < 16 {
+        routes[i] = IsaRoute::identity(i as u8);
+        i += 1;
+    }
+    routes
+});
+
+/// Override the routing of an ISA IRQ
+///
+/// Overrides of IRQs outside the ISA range are reported by broken firmware, they are logged
+/// and ignored.
+pub fn set_isa_route(isa_irq: u8, route: IsaRoute) {
+    let mut routes = ISA_ROUTES.lock();
+    let Some(slot) = routes.get_mut(isa_irq as usize) else {
+        log::warn!("ignoring override of non-isa irq {isa_irq} -> {route:?}");
+        return;
+    };
+    log::debug!("isa irq {isa_irq} -> {route:?}");
+    *slot = route;
+}
+
+/// Returns the routing of an ISA IRQ
+pub fn isa_route(isa_irq: u8) -> IsaRoute {
+    ISA_ROUTES.lock()[isa_irq as usize]
+}
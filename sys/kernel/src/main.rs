@@ -68,9 +68,13 @@ mod prelude {
 }
 
 mod arch;
+mod cmdline;
 mod cpu;
+mod intr;
 mod panic;
+mod syscall;
 mod test;
+mod time;
 mod trap;
 
 /// Main machine-independent kernel entry point
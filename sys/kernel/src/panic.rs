@@ -30,5 +30,8 @@
 
 #[panic_handler]
 fn rust_panic(_info: &core::panic::PanicInfo) -> ! {
+    crate::trap::freeze_debug_state();
+    crate::trap::dump_debug_state();
+
     loop {}
 }
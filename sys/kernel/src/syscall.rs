@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! System Calls
+//!
+//! The architecture decodes the call number and arguments from the trapping context and
+//! hands them to [`dispatch()`]. Results are returned as a single word: non-negative on
+//! success, or the negated [`Error`] code on failure.
+
+/// Arguments to a system call
+pub type Args = [usize; 6];
+
+pub type Result = core::result::Result<usize, Error>;
+
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Bad address
+    Fault   = 14,
+    /// Invalid argument
+    Invalid = 22,
+    /// No such system call
+    NoSys   = 38,
+}
+
+type Handler = fn(&Args) -> Result;
+
+/// System call numbers
+pub mod nr {
+    pub const NULL: usize = 0;
+}
+
+static SYSCALL_TABLE: [Option<Handler>; 1] = [
+    /* nr::NULL */ Some(sys_null),
+];
+
+/// Execute system call `number`
+pub fn dispatch(number: usize, args: &Args) -> isize {
+    let result = match SYSCALL_TABLE.get(number).copied().flatten() {
+        Some(handler) => handler(args),
+        None => Err(Error::NoSys),
+    };
+
+    match result {
+        Ok(value) => value as isize,
+        Err(error) => -(error as isize),
+    }
+}
+
+/// Do nothing
+///
+/// Useful for measuring the overhead of the system call path.
+fn sys_null(_: &Args) -> Result {
+    Ok(0)
+}
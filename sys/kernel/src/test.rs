@@ -50,3 +50,23 @@ pub fn run(tests: &[&Case]) {
     log::info!("tests completed successfully");
     log::info!("suppressed output {suppressed} test cases");
 }
+
+/// Register functions of the calling module as test cases
+///
+/// Each case is named after the module path and the function.
+#[macro_export]
+macro_rules! test_cases {
+    ($($func:ident),* $(,)?) => {
+        mod test_cases {
+            $(
+                #[test_case]
+                #[allow(non_upper_case_globals)]
+                static $func: $crate::test::Case = $crate::test::Case {
+                    name:  concat!(module_path!(), "::", stringify!($func)),
+                    func:  super::$func,
+                    quiet: false,
+                };
+            )*
+        }
+    };
+}
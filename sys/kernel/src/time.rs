@@ -0,0 +1,421 @@
+/*
+ * Copyright (c) 2023 xvanc <xvancm@gmail.com>
+ *
+ * Redistribution and use in source and binary forms, with or without modification,
+ * are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice,
+ *    this list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * 3. Neither the name of the copyright holder nor the names of its contributors
+ *    may be used to endorse or promote products derived from this software without
+ *    specific prior written permission.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY
+ * EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES
+ * OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE DISCLAIMED.
+ * IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT,
+ * INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+ * PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS
+ * INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ *
+ * SPDX-License-Identifier: BSD-3-Clause
+ */
+
+//! Timekeeping
+//!
+//! Time is kept by the highest-rated [`ClockSource`] registered so far. Its counter is
+//! extended to 64 bits in software, so sources which wrap quickly remain usable. The
+//! architecture calls [`update()`] every [`UPDATE_INTERVAL_NS`] to fold the counter in
+//! before it can wrap twice. Readings of 64-bit counters which appear to go backwards,
+//! because the counters of different CPUs are not perfectly in sync, are clamped.
+//!
+//! Timer interrupts are provided by [`EventSource`]s, selected the same way.
+
+use core::{
+    cmp,
+    sync::atomic::{self, AtomicPtr, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    sync::{mutex::MutexKind, Mutex},
+    trap,
+};
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Interval at which [`update()`] must be called
+///
+/// Clock sources must not wrap in less than twice this interval.
+pub const UPDATE_INTERVAL_NS: u64 = NANOS_PER_SEC;
+
+/// A free-running counter
+pub trait ClockSource: Sync {
+    fn name(&self) -> &str;
+
+    /// Returns the current value of the counter
+    fn read(&self) -> u64;
+
+    /// Returns the frequency of the counter, in Hz
+    fn frequency(&self) -> u64;
+
+    /// Returns the mask of the implemented bits of the counter
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    /// Returns the quality of the source, relative to other sources
+    ///
+    /// Higher is better. Sources which are unusable should return zero.
+    fn rating(&self) -> u32;
+}
+
+/// Called from interrupt context when an [`EventSource`] fires
+pub type EventHandler = fn();
+
+/// A timer which raises an interrupt after a programmable delay
+pub trait EventSource: Sync {
+    fn name(&self) -> &str;
+
+    /// Set the function called when the timer fires
+    fn set_handler(&self, handler: EventHandler);
+
+    /// Fire once, after `ns` nanoseconds
+    fn oneshot(&self, ns: u64);
+
+    /// Returns `true` if the timer can fire periodically without being reprogrammed
+    fn supports_periodic(&self) -> bool;
+
+    /// Fire every `ns` nanoseconds
+    ///
+    /// # Panics
+    ///
+    /// This method may panic if [`supports_periodic()`](EventSource::supports_periodic)
+    /// returns `false`.
+    fn periodic(&self, ns: u64);
+
+    /// Cancel any pending event
+    fn stop(&self);
+
+    /// Returns the quality of the source, relative to other sources
+    fn rating(&self) -> u32;
+}
+
+/// The clock, published through a sequence lock
+///
+/// Readers never block, so the time can be read from any context, including interrupt
+/// handlers. Writers make `seq` odd while they update the other fields, with interrupts
+/// disabled so that a reader on the same CPU cannot spin on them forever.
+struct Clock {
+    seq:       AtomicU64,
+    /// The current source, or null
+    source:    AtomicPtr<&'static dyn ClockSource>,
+    /// Counter value as of the last update
+    last:      AtomicU64,
+    /// Cycles elapsed since the source was installed, as of the last update
+    cycles:    AtomicU64,
+    /// Nanoseconds since boot at the time the source was installed
+    base_ns:   AtomicU64,
+    frequency: AtomicU64,
+    /// Latest time returned, which keeps the clock monotonic when the counters of different
+    /// CPUs are not perfectly in sync
+    latest_ns: AtomicU64,
+}
+
+/// A consistent copy of the fields of [`Clock`]
+struct Snapshot {
+    source:    Option<&'static dyn ClockSource>,
+    last:      u64,
+    cycles:    u64,
+    base_ns:   u64,
+    frequency: u64,
+}
+
+static CLOCK: Clock = Clock::new();
+
+impl Clock {
+    const fn new() -> Clock {
+        Clock {
+            seq:       AtomicU64::new(0),
+            source:    AtomicPtr::new(core::ptr::null_mut()),
+            last:      AtomicU64::new(0),
+            cycles:    AtomicU64::new(0),
+            base_ns:   AtomicU64::new(0),
+            frequency: AtomicU64::new(0),
+            latest_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a consistent snapshot and the sequence number it was taken at
+    fn snapshot(&self) -> (u64, Snapshot) {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 != 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let snapshot = Snapshot {
+                source:    unsafe { self.source.load(Ordering::Relaxed).as_ref().copied() },
+                last:      self.last.load(Ordering::Relaxed),
+                cycles:    self.cycles.load(Ordering::Relaxed),
+                base_ns:   self.base_ns.load(Ordering::Relaxed),
+                frequency: self.frequency.load(Ordering::Relaxed),
+            };
+
+            atomic::fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return (seq, snapshot);
+            }
+        }
+    }
+
+    /// Start updating the fields, if they have not changed since `seq`
+    ///
+    /// Interrupts must be disabled until the matching [`Clock::end_write()`].
+    fn try_begin_write(&self, seq: u64) -> bool {
+        let locked = self
+            .seq
+            .compare_exchange(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        atomic::fence(Ordering::Release);
+        locked
+    }
+
+    fn end_write(&self, seq: u64) {
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+
+    fn now(&self) -> u64 {
+        let (seq, snapshot) = self.snapshot();
+        let Some(source) = snapshot.source else {
+            return snapshot.base_ns;
+        };
+
+        // A 64-bit counter never wraps, a reading behind the last one comes from a CPU whose
+        // counter is slightly out of sync. Narrower counters are shared by all CPUs and
+        // folded in by `update()` before they can wrap twice.
+        let now = source.read();
+        let mask = source.mask();
+        let delta = match now.wrapping_sub(snapshot.last) & mask {
+            delta if mask == u64::MAX && (delta as i64) < 0 => 0,
+            delta => delta,
+        };
+        let cycles = snapshot.cycles + delta;
+
+        // Fold the reading in so sources which wrap quickly keep counting. If another CPU is
+        // already doing so, its update is just as good.
+        if delta != 0 {
+            let token = trap::disable();
+            if self.try_begin_write(seq) {
+                self.last.store(now, Ordering::Relaxed);
+                self.cycles.store(cycles, Ordering::Relaxed);
+                self.end_write(seq);
+            }
+            trap::enable(token);
+        }
+
+        let elapsed = cycles as u128 * NANOS_PER_SEC as u128 / snapshot.frequency as u128;
+        let ns = snapshot.base_ns + elapsed as u64;
+        cmp::max(ns, self.latest_ns.fetch_max(ns, Ordering::Relaxed))
+    }
+
+    /// Start keeping time with `source`, continuing from the current time
+    fn install(&self, source: &'static dyn ClockSource) {
+        let token = trap::disable();
+        let now = self.now();
+        let seq = loop {
+            let (seq, _) = self.snapshot();
+            if self.try_begin_write(seq) {
+                break seq;
+            }
+        };
+
+        self.source.store(Box::leak(Box::new(source)), Ordering::Relaxed);
+        self.last.store(source.read(), Ordering::Relaxed);
+        self.cycles.store(0, Ordering::Relaxed);
+        self.base_ns.store(now, Ordering::Relaxed);
+        self.frequency.store(source.frequency(), Ordering::Relaxed);
+
+        self.end_write(seq);
+        trap::enable(token);
+    }
+}
+
+/// Serializes [`register_clocksource()`] and [`register_eventsource()`]
+static REGISTER_LOCK: Mutex<()> = Mutex::new(MutexKind::Spin, ());
+
+/// Register a clock source
+///
+/// The source is used for timekeeping if it is rated higher than the current one.
+pub fn register_clocksource(source: &'static dyn ClockSource) {
+    let _guard = REGISTER_LOCK.lock();
+
+    if source.rating() == 0 || clocksource().is_some_and(|cur| cur.rating() >= source.rating()) {
+        return;
+    }
+
+    let wrap_ns = (source.mask() as u128 + 1) * NANOS_PER_SEC as u128 / source.frequency() as u128;
+    if wrap_ns < 2 * UPDATE_INTERVAL_NS as u128 {
+        log::warn!("time: clock source `{}` wraps too quickly, ignoring it", source.name());
+        return;
+    }
+
+    log::info!(
+        "time: using clock source `{}` ({} kHz)",
+        source.name(),
+        source.frequency() / 1000
+    );
+    CLOCK.install(source);
+}
+
+/// Fold the counter of the clock source in
+///
+/// Must be called at least every [`UPDATE_INTERVAL_NS`], so the counter cannot wrap twice
+/// between readings. This is safe to call from any context.
+pub fn update() {
+    CLOCK.now();
+}
+
+/// The current event source, or null
+static EVENT_SOURCE: AtomicPtr<&'static dyn EventSource> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Register an event source
+///
+/// The source is used for timer interrupts if it is rated higher than the current one.
+pub fn register_eventsource(source: &'static dyn EventSource) {
+    let _guard = REGISTER_LOCK.lock();
+
+    if eventsource().is_some_and(|cur| cur.rating() >= source.rating()) {
+        return;
+    }
+
+    log::info!("time: using event source `{}`", source.name());
+    let old = EVENT_SOURCE.swap(Box::leak(Box::new(source)), Ordering::AcqRel);
+    if let Some(old) = unsafe { old.as_ref() } {
+        old.stop();
+    }
+}
+
+/// Returns the event source currently used for timer interrupts
+///
+/// This is safe to call from any context.
+pub fn eventsource() -> Option<&'static dyn EventSource> {
+    unsafe { EVENT_SOURCE.load(Ordering::Acquire).as_ref().copied() }
+}
+
+/// Returns the clock source currently used for timekeeping
+pub fn clocksource() -> Option<&'static dyn ClockSource> {
+    CLOCK.snapshot().1.source
+}
+
+/// Returns the number of nanoseconds since boot
+///
+/// The value never decreases. It does not advance until a clock source is registered.
+pub fn nanotime() -> u64 {
+    CLOCK.now()
+}
+
+/// Returns the time since boot
+pub fn uptime() -> Duration {
+    Duration::from_nanos(nanotime())
+}
+
+/// Busy-wait for at least `duration`
+///
+/// # Panics
+///
+/// This function will panic if there is no clock source.
+pub fn delay(duration: Duration) {
+    assert!(clocksource().is_some(), "time::delay() called without a clock source");
+
+    let end = nanotime() + duration.as_nanos() as u64;
+    while nanotime() < end {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::test_cases!(clock_wrap, clock_backwards);
+
+    /// A counter advanced by hand, ticking every millisecond
+    struct TestSource {
+        counter: AtomicU64,
+        mask:    u64,
+    }
+
+    impl TestSource {
+        fn advance(&self, ticks: u64) {
+            self.counter.fetch_add(ticks, Ordering::Relaxed);
+        }
+    }
+
+    impl ClockSource for TestSource {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn read(&self) -> u64 {
+            self.counter.load(Ordering::Relaxed) & self.mask
+        }
+
+        fn frequency(&self) -> u64 {
+            1000
+        }
+
+        fn mask(&self) -> u64 {
+            self.mask
+        }
+
+        fn rating(&self) -> u32 {
+            1
+        }
+    }
+
+    /// Run an 8-bit counter through several wraps, reading it every 100 ticks
+    fn clock_wrap() {
+        static SOURCE: TestSource = TestSource {
+            counter: AtomicU64::new(0xf0),
+            mask:    0xff,
+        };
+
+        let clock = Clock::new();
+        clock.install(&SOURCE);
+        assert_eq!(clock.now(), 0);
+
+        for i in 1..=10 {
+            SOURCE.advance(100);
+            assert_eq!(clock.now(), i * 100_000_000);
+        }
+    }
+
+    /// Read a 64-bit counter which goes backwards, as if read on another CPU
+    fn clock_backwards() {
+        static SOURCE: TestSource = TestSource {
+            counter: AtomicU64::new(1000),
+            mask:    u64::MAX,
+        };
+
+        let clock = Clock::new();
+        clock.install(&SOURCE);
+
+        SOURCE.advance(500);
+        assert_eq!(clock.now(), 500_000_000);
+
+        SOURCE.counter.fetch_sub(100, Ordering::Relaxed);
+        assert_eq!(clock.now(), 500_000_000);
+
+        SOURCE.advance(200);
+        assert_eq!(clock.now(), 600_000_000);
+    }
+}
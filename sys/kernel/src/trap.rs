@@ -28,17 +28,36 @@
  * SPDX-License-Identifier: BSD-3-Clause
  */
 
-use crate::arch;
+use core::{
+    fmt, mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{arch, vm::VirtAddr};
 
 pub trait ArchTrap {
     type DisableToken: Copy;
+    type Frame: fmt::Display;
 
     fn disable() -> Self::DisableToken;
     fn enable(token: Self::DisableToken);
+
+    /// Returns `true` if `frame` was saved while executing user code
+    fn from_user(frame: &Self::Frame) -> bool;
+
+    /// Stop recording state which describes how the current CPU got here, such as a trace of
+    /// the last branches taken, so the crash handler does not overwrite it
+    fn freeze_debug_state() {}
+
+    /// Print the state preserved by [`ArchTrap::freeze_debug_state()`]
+    fn dump_debug_state() {}
 }
 
 pub type DisableToken = <arch::ThisArch as ArchTrap>::DisableToken;
 
+/// Saved register state of an interrupted context
+pub type TrapFrame = <arch::ThisArch as ArchTrap>::Frame;
+
 pub fn disable() -> DisableToken {
     <arch::ThisArch as ArchTrap>::disable()
 }
@@ -46,3 +65,104 @@ pub fn disable() -> DisableToken {
 pub fn enable(token: DisableToken) {
     <arch::ThisArch as ArchTrap>::enable(token);
 }
+
+/// Preserve the architecture's record of how the current CPU got here, ahead of a crash
+pub fn freeze_debug_state() {
+    <arch::ThisArch as ArchTrap>::freeze_debug_state();
+}
+
+/// Print the state preserved by [`freeze_debug_state()`]
+pub fn dump_debug_state() {
+    <arch::ThisArch as ArchTrap>::dump_debug_state();
+}
+
+/// The kind of access which caused a fault
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PageFault {
+    /// The virtual address which caused the fault
+    pub addr:    VirtAddr,
+    pub access:  Access,
+    /// The fault occurred while executing in user mode
+    pub user:    bool,
+    /// The fault was caused by a protection violation rather than a missing translation
+    pub present: bool,
+}
+
+/// Synchronous exceptions, as seen by the machine-independent kernel
+#[derive(Clone, Copy, Debug)]
+pub enum Exception {
+    Arithmetic,
+    Breakpoint,
+    Debug,
+    IllegalInstruction,
+    ProtectionFault,
+    PageFault(PageFault),
+    Alignment,
+    MachineCheck,
+    /// An exception without a machine-independent meaning
+    ///
+    /// These are always fatal.
+    Other,
+}
+
+/// Handles an exception taken by user code
+///
+/// The handler decides what happens to the faulting task, for example delivering a signal
+/// or killing it. When it returns, execution resumes with the state in the frame.
+pub type UserExceptionHandler = fn(&mut TrapFrame, Exception);
+
+/// The [`UserExceptionHandler`], or zero if none is installed
+static USER_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Install the handler for exceptions taken by user code
+pub fn set_user_handler(handler: UserExceptionHandler) {
+    USER_HANDLER.store(handler as usize, Ordering::Release);
+}
+
+/// Machine-independent exception dispatch
+///
+/// This is called by the architecture's trap handler with the saved state of the
+/// interrupted context.
+pub fn exception(frame: &mut TrapFrame, exception: Exception) {
+    let user = <arch::ThisArch as ArchTrap>::from_user(frame);
+
+    // Exceptions without a meaning to the task, such as machine checks, are the kernel's
+    // problem even when user code was interrupted.
+    match exception {
+        Exception::MachineCheck | Exception::Other => fatal(frame, exception),
+        _ if !user => fatal(frame, exception),
+        _ => user_exception(frame, exception),
+    }
+}
+
+fn user_exception(frame: &mut TrapFrame, exception: Exception) {
+    match USER_HANDLER.load(Ordering::Acquire) {
+        0 => {
+            // There is no task to signal, abandon the user context. The CPU stays available
+            // for interrupts and IPIs.
+            log::warn!("user exception: {exception:?}, abandoning user context");
+            log::warn!("{frame}");
+            arch::idle();
+        }
+        handler => {
+            let handler = unsafe { mem::transmute::<usize, UserExceptionHandler>(handler) };
+            handler(frame, exception);
+        }
+    }
+}
+
+/// Report an unrecoverable exception and halt the current CPU
+pub fn fatal(frame: &TrapFrame, exception: Exception) -> ! {
+    freeze_debug_state();
+    log::error!("fatal exception: {exception:?}");
+    log::error!("{frame}");
+    dump_debug_state();
+    arch::hcf();
+}
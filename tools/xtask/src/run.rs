@@ -15,6 +15,10 @@ pub struct RunArgs {
     #[arg(long)]
     pub no_accel: bool,
 
+    /// Number of CPUs to emulate
+    #[arg(long, default_value_t = 1)]
+    pub smp: u32,
+
     #[arg(last = true)]
     pub emulator_args: Vec<OsString>,
 }
@@ -44,6 +48,7 @@ pub fn main(session: &mut Session, args: &mut RunArgs) -> anyhow::Result<()> {
     }
 
     qemu.args(["-no-reboot", "-no-shutdown", "-serial", "mon:stdio"]);
+    qemu.arg("-smp").arg(args.smp.to_string());
 
     if let Some(log_file) = args.log.as_ref() {
         let log_file = if let Some(path) = log_file.as_ref() {